@@ -0,0 +1,172 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_ADDRESS_BOOK: &str = "peers.json";
+/// Peers not seen for this long are dropped from the book.
+pub const FORGET_AFTER_SECS: u64 = 30 * 24 * 60 * 60;
+pub const MAX_ADDRS_PER_PEER: usize = 8;
+/// How many of the most recently seen peers are dialed when reconnecting.
+pub const RECONNECT_PEERS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerRecord {
+    pub addrs: Vec<String>,
+    /// Unix seconds of the last connection or identify exchange.
+    pub last_seen: u64,
+}
+
+// Known peers, keyed by peer id, kept in a JSON file between runs
+#[derive(Debug, Clone)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: BTreeMap<String, PeerRecord>,
+    dirty: bool,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl AddressBook {
+    /// A book which is never written to disk.
+    pub fn in_memory() -> Self {
+        AddressBook {
+            path: None,
+            peers: BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Reads the book at `path`, a missing file gives an empty book.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let peers = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(AddressBook {
+            path: Some(path),
+            peers,
+            dirty: false,
+        })
+    }
+
+    /// Writes the book if anything changed since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        let data = serde_json::to_vec_pretty(&self.peers).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Write then rename, so a crash never leaves a half written book behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Records that `peer` was seen at `now`, reachable at `addr` if given.
+    pub fn record(&mut self, peer: PeerId, addr: Option<&Multiaddr>, now: u64) {
+        let record = self.peers.entry(peer.to_string()).or_insert(PeerRecord {
+            addrs: vec![],
+            last_seen: now,
+        });
+        record.last_seen = record.last_seen.max(now);
+        if let Some(addr) = addr {
+            let addr = addr.to_string();
+            // Most recent address first
+            record.addrs.retain(|a| *a != addr);
+            record.addrs.insert(0, addr);
+            record.addrs.truncate(MAX_ADDRS_PER_PEER);
+        }
+        self.dirty = true;
+    }
+
+    /// Forgets peers which were not seen for `FORGET_AFTER_SECS`.
+    pub fn prune(&mut self, now: u64) {
+        let before = self.peers.len();
+        self.peers
+            .retain(|_, record| now.saturating_sub(record.last_seen) < FORGET_AFTER_SECS);
+        if self.peers.len() != before {
+            self.dirty = true;
+        }
+    }
+
+    /// Known peers with their addresses, most recently seen first.
+    /// Entries that no longer parse are skipped.
+    pub fn peers(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut records: Vec<_> = self.peers.iter().collect();
+        records.sort_by_key(|(_, record)| std::cmp::Reverse(record.last_seen));
+        records
+            .into_iter()
+            .filter_map(|(peer, record)| {
+                let peer = peer.parse().ok()?;
+                let addrs = record.addrs.iter().filter_map(|a| a.parse().ok()).collect();
+                Some((peer, addrs))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address_book::{AddressBook, FORGET_AFTER_SECS, MAX_ADDRS_PER_PEER};
+    use libp2p::{Multiaddr, PeerId};
+    use std::{env, fs, process};
+
+    fn addr(port: usize) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn test_address_book() {
+        let path = env::temp_dir().join(format!("elemchain-test-{}-peers.json", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut book = AddressBook::load(&path).unwrap();
+        assert!(book.is_empty());
+
+        let old = PeerId::random();
+        let recent = PeerId::random();
+        book.record(old, Some(&addr(4001)), 100);
+        book.record(recent, Some(&addr(4002)), 200);
+        for port in 5000..5000 + MAX_ADDRS_PER_PEER + 2 {
+            book.record(recent, Some(&addr(port)), 150);
+        }
+        // Older sightings don't move last seen back, so `recent` still comes first
+        let (peer, addrs) = &book.peers()[0];
+        assert_eq!(*peer, recent);
+        assert_eq!(addrs.len(), MAX_ADDRS_PER_PEER);
+        assert_eq!(addrs[0], addr(5000 + MAX_ADDRS_PER_PEER + 1));
+
+        book.save().unwrap();
+        let mut loaded = AddressBook::load(&path).unwrap();
+        let peers = loaded.peers();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].0, recent);
+        assert_eq!(peers[1], (old, vec![addr(4001)]));
+
+        loaded.prune(100 + FORGET_AFTER_SECS);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.peers()[0].0, recent);
+        fs::remove_file(&path).unwrap();
+    }
+}
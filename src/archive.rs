@@ -0,0 +1,391 @@
+use crate::{block::Block, blockchain::Blockchain, error::ValidationError, node::{Node, Resolution}};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Archive layout: one JSON header line followed by one JSON line per block.
+// Every block line carries its height and a sha256 checksum of the serialized block,
+// so a truncated or edited file is caught before anything touches the node.
+pub const ARCHIVE_MAGIC: &str = "elemchain-archive";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ArchiveHeader {
+    pub magic: String,
+    pub version: u32,
+    pub start_height: usize,
+    pub end_height: usize,
+    pub block_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ArchiveEntry {
+    height: usize,
+    checksum: String,
+    block: Block,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    BadMagic(String),
+    UnsupportedVersion(u32),
+    InvalidRange { from: usize, to: usize, len: usize },
+    ChecksumMismatch(usize),
+    UnexpectedHeight { expected: usize, found: usize },
+    WrongBlockCount { expected: usize, found: usize },
+    InvalidBlock { height: usize, error: ValidationError },
+    DoesNotConnect { start_height: usize, local_len: usize },
+    InvalidChain,
+    /// Valid on its own, but not a chain of our network.
+    Rejected(ValidationError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Io(e) => write!(f, "io error: {}", e),
+            ArchiveError::Json(e) => write!(f, "malformed archive: {}", e),
+            ArchiveError::BadMagic(magic) => write!(f, "not a chain archive (magic {:?})", magic),
+            ArchiveError::UnsupportedVersion(v) => write!(f, "unsupported archive version {}", v),
+            ArchiveError::InvalidRange { from, to, len } => {
+                write!(f, "invalid height range {}..={} for chain of length {}", from, to, len)
+            }
+            ArchiveError::ChecksumMismatch(h) => write!(f, "checksum mismatch for block at height {}", h),
+            ArchiveError::UnexpectedHeight { expected, found } => {
+                write!(f, "expected block at height {}, found {}", expected, found)
+            }
+            ArchiveError::WrongBlockCount { expected, found } => {
+                write!(f, "header announces {} blocks, file has {}", expected, found)
+            }
+            ArchiveError::InvalidBlock { height, error } => write!(f, "block at height {}: {}", height, error),
+            ArchiveError::DoesNotConnect { start_height, local_len } => write!(
+                f,
+                "archive starts at height {} but local chain has only {} blocks",
+                start_height, local_len
+            ),
+            ArchiveError::InvalidChain => write!(f, "imported blocks do not form a valid chain"),
+            ArchiveError::Rejected(reason) => write!(f, "imported chain rejected: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(e: io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(e: serde_json::Error) -> Self {
+        ArchiveError::Json(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportSummary {
+    pub start_height: usize,
+    pub imported: usize,
+    pub adopted: bool,
+}
+
+pub fn block_checksum(block: &Block) -> Result<String, ArchiveError> {
+    let json = serde_json::to_string(block)?;
+    Ok(format!("{:x}", Sha256::new().chain_update(json).finalize()))
+}
+
+/// Writes blocks `from..=to` of `chain` to `writer`. Returns the number of blocks written.
+pub fn export_chain<W: Write>(
+    chain: &Blockchain,
+    from: usize,
+    to: usize,
+    mut writer: W,
+) -> Result<usize, ArchiveError> {
+    if from > to || to >= chain.len() {
+        return Err(ArchiveError::InvalidRange {
+            from,
+            to,
+            len: chain.len(),
+        });
+    }
+
+    let header = ArchiveHeader {
+        magic: ARCHIVE_MAGIC.to_string(),
+        version: ARCHIVE_VERSION,
+        start_height: from,
+        end_height: to,
+        block_count: to - from + 1,
+    };
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+
+    for (height, block) in chain.chain[from..=to].iter().enumerate() {
+        let entry = ArchiveEntry {
+            height: from + height,
+            checksum: block_checksum(block)?,
+            block: block.clone(),
+        };
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(header.block_count)
+}
+
+pub fn export_to_file<P: AsRef<Path>>(
+    chain: &Blockchain,
+    from: usize,
+    to: usize,
+    path: P,
+) -> Result<usize, ArchiveError> {
+    let file = File::create(path)?;
+    export_chain(chain, from, to, BufWriter::new(file))
+}
+
+/// Replaces the archive at `path` with the whole chain. Written to a temporary file
+/// first, so a crash midway leaves the previous archive intact.
+pub fn save_chain<P: AsRef<Path>>(chain: &Blockchain, path: P) -> Result<usize, ArchiveError> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    let count = export_to_file(chain, 0, chain.len().saturating_sub(1), &tmp)?;
+    fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+/// Parses an archive and checks its header, heights and per-block checksums.
+/// Chain level validation happens in `import_archive`.
+pub fn read_archive<R: BufRead>(reader: R) -> Result<(ArchiveHeader, Vec<Block>), ArchiveError> {
+    let mut lines = reader.lines();
+
+    let header_line = match lines.next() {
+        Some(line) => line?,
+        None => return Err(ArchiveError::BadMagic(String::new())),
+    };
+    let header: ArchiveHeader = serde_json::from_str(&header_line)?;
+    if header.magic != ARCHIVE_MAGIC {
+        return Err(ArchiveError::BadMagic(header.magic));
+    }
+    if header.version != ARCHIVE_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(header.version));
+    }
+
+    let mut blocks = vec![];
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ArchiveEntry = serde_json::from_str(&line)?;
+        let expected = header.start_height + blocks.len();
+        if entry.height != expected {
+            return Err(ArchiveError::UnexpectedHeight {
+                expected,
+                found: entry.height,
+            });
+        }
+        if entry.checksum != block_checksum(&entry.block)? {
+            return Err(ArchiveError::ChecksumMismatch(entry.height));
+        }
+        blocks.push(entry.block);
+    }
+
+    if blocks.len() != header.block_count
+        || header.end_height + 1 != header.start_height + header.block_count
+    {
+        return Err(ArchiveError::WrongBlockCount {
+            expected: header.block_count,
+            found: blocks.len(),
+        });
+    }
+
+    Ok((header, blocks))
+}
+
+/// Validates an archive against the node's chain and hands it to conflict resolution,
+/// so a shorter archive never replaces a longer local chain.
+pub fn import_archive<R: BufRead>(node: &mut Node, reader: R) -> Result<ImportSummary, ArchiveError> {
+    let (header, blocks) = read_archive(reader)?;
+
+    let local_len = node.blockchain.len();
+    if header.start_height > local_len {
+        return Err(ArchiveError::DoesNotConnect {
+            start_height: header.start_height,
+            local_len,
+        });
+    }
+
+    let difficulty = node.blockchain.difficulty();
+    for (i, block) in blocks.iter().enumerate() {
+        let height = header.start_height + i;
+        let parent = match i {
+            0 => height.checked_sub(1).and_then(|parent| node.blockchain.chain.get(parent)),
+            _ => Some(&blocks[i - 1]),
+        };
+        let checked = match parent {
+            Some(parent) => block.validate(difficulty).and_then(|()| block.validate_parent(parent)),
+            // The genesis block is fixed rather than mined, conflict resolution compares it with ours
+            None if block.has_valid_hash() => Ok(()),
+            None => Err(ValidationError::InvalidHash),
+        };
+        checked.map_err(|error| ArchiveError::InvalidBlock { height, error })?;
+    }
+
+    let imported = blocks.len();
+    let candidate = node.blockchain.fork_at(header.start_height, blocks);
+    if !candidate.is_valid() {
+        return Err(ArchiveError::InvalidChain);
+    }
+
+    let adopted = match node.resolve_chain_conflict(&candidate) {
+        Resolution::Rejected(reason) => return Err(ArchiveError::Rejected(reason)),
+        resolution => matches!(resolution, Resolution::Switched { .. }),
+    };
+
+    Ok(ImportSummary {
+        start_height: header.start_height,
+        imported,
+        adopted,
+    })
+}
+
+pub fn import_from_file<P: AsRef<Path>>(node: &mut Node, path: P) -> Result<ImportSummary, ArchiveError> {
+    let file = File::open(path)?;
+    import_archive(node, BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{export_chain, import_archive, import_from_file, read_archive, save_chain, ArchiveError};
+    use crate::{blockchain::Blockchain, error::ValidationError, node::Node, transaction::Transaction};
+    use std::time::SystemTime;
+    use std::{env, fs, process};
+
+    fn mined_chain(blocks: usize) -> Blockchain {
+        let mut chain = Blockchain::new(1, 1, 256);
+        for i in 0..blocks {
+            let tx = Transaction {
+                from: String::from("Alice"),
+                to: String::from("Bob"),
+                time: SystemTime::now(),
+                amount: i as i32,
+            };
+            assert!(chain.try_mine(vec![tx]).is_ok());
+        }
+        chain
+    }
+
+    fn empty_node() -> Node {
+        Node::new(Blockchain::new(1, 1, 256))
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let chain = mined_chain(4);
+        let mut file = vec![];
+        assert_eq!(export_chain(&chain, 0, 3, &mut file).unwrap(), 4);
+
+        let mut node = empty_node();
+        let summary = import_archive(&mut node, &file[..]).unwrap();
+        assert_eq!(summary.imported, 4);
+        assert!(summary.adopted);
+        assert!(node.blockchain == chain);
+    }
+
+    #[test]
+    fn test_partial_range_connects_to_local_chain() {
+        let chain = mined_chain(5);
+        let mut file = vec![];
+        export_chain(&chain, 2, 4, &mut file).unwrap();
+
+        let mut node = empty_node();
+        node.blockchain = chain.fork_at(2, vec![]);
+        let summary = import_archive(&mut node, &file[..]).unwrap();
+        assert_eq!(summary.start_height, 2);
+        assert!(node.blockchain == chain);
+
+        let mut disconnected = empty_node();
+        assert!(matches!(
+            import_archive(&mut disconnected, &file[..]),
+            Err(ArchiveError::DoesNotConnect { .. })
+        ));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let chain = mined_chain(2);
+        let mut file = vec![];
+        export_chain(&chain, 0, 1, &mut file).unwrap();
+
+        let tampered = String::from_utf8(file.clone())
+            .unwrap()
+            .replace("\"amount\":1", "\"amount\":1000");
+        assert!(matches!(
+            read_archive(tampered.as_bytes()),
+            Err(ArchiveError::ChecksumMismatch(1))
+        ));
+
+        let truncated: Vec<&str> = std::str::from_utf8(&file).unwrap().lines().take(2).collect();
+        assert!(matches!(
+            read_archive(truncated.join("\n").as_bytes()),
+            Err(ArchiveError::WrongBlockCount { .. })
+        ));
+
+        let future = String::from_utf8(file)
+            .unwrap()
+            .replacen("\"version\":1", "\"version\":99", 1);
+        assert!(matches!(
+            read_archive(future.as_bytes()),
+            Err(ArchiveError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_blocks_are_validated() {
+        let chain = mined_chain(3);
+        // Linked and hashed correctly, but without the work behind them
+        let mut unmined = chain.clone();
+        for height in 1..unmined.len() {
+            let prev_hash = unmined.chain[height - 1].hash.clone();
+            let block = &mut unmined.chain[height];
+            block.prev_hash = prev_hash;
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        let mut file = vec![];
+        export_chain(&unmined, 0, 2, &mut file).unwrap();
+
+        let mut node = Node::new(Blockchain::new(1, 4, 256));
+        assert!(matches!(
+            import_archive(&mut node, &file[..]),
+            Err(ArchiveError::InvalidBlock { height: 1, error: ValidationError::InsufficientWork { .. } })
+        ));
+        assert!(node.blockchain.is_empty());
+    }
+
+    #[test]
+    fn test_save_chain() {
+        let path = env::temp_dir().join(format!("elemchain-test-{}.archive", process::id()));
+        let chain = mined_chain(2);
+        assert_eq!(save_chain(&chain, &path).unwrap(), 2);
+        assert_eq!(save_chain(&mined_chain(3), &path).unwrap(), 3);
+
+        let mut node = empty_node();
+        assert_eq!(import_from_file(&mut node, &path).unwrap().imported, 3);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_range() {
+        let chain = mined_chain(2);
+        assert!(matches!(
+            export_chain(&chain, 1, 2, vec![]),
+            Err(ArchiveError::InvalidRange { .. })
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error::ValidationError;
 use crate::transaction::Transaction;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -5,6 +6,58 @@ use std::cmp::PartialEq;
 use std::fmt;
 use std::time::SystemTime;
 
+/// Largest encoded size of a block.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Encoded size of `value` on its own, used to fill blocks and messages up to their limits.
+pub fn encoded_size<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|data| data.len()).unwrap_or(usize::MAX)
+}
+
+// Everything needed to check a block's hash and proof of work without its transactions.
+// Transactions are committed to by `tx_root`, so the header hash covers them too.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BlockHeader {
+    pub hash: String,
+    pub prev_hash: String,
+    pub tx_root: String,
+    pub time: SystemTime,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> String {
+        let header_string =
+            serde_json::to_string(&(&self.prev_hash, &self.tx_root, &self.time, self.nonce));
+
+        let hashed = Sha256::new().chain_update(header_string.unwrap()).finalize();
+
+        format!("{:x}", hashed)
+    }
+
+    pub fn has_valid_hash(&self) -> bool {
+        self.hash == self.calculate_hash()
+    }
+
+    pub fn meets_difficulty(&self, difficulty: usize) -> bool {
+        self.hash.len() >= difficulty && self.hash[..difficulty].bytes().all(|b| b == b'0')
+    }
+
+    /// Expected number of hashes needed to find this hash: 16 per leading zero hex digit.
+    pub fn work(&self) -> u128 {
+        let zeros = self.hash.bytes().take_while(|b| *b == b'0').count();
+        16u128.pow(zeros.min(31) as u32)
+    }
+}
+
+pub fn calculate_tx_root(txs: &[Transaction]) -> String {
+    let txs_string = serde_json::to_string(txs);
+
+    let hashed = Sha256::new().chain_update(txs_string.unwrap()).finalize();
+
+    format!("{:x}", hashed)
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Block {
     pub hash: String,
@@ -20,22 +73,67 @@ impl Block {
             hash: String::new(),
             prev_hash: prev,
             transactions: txs,
-            nonce: nonce,
+            nonce,
             time: ms,
         }
     }
 
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            hash: self.hash.clone(),
+            prev_hash: self.prev_hash.clone(),
+            tx_root: calculate_tx_root(&self.transactions),
+            time: self.time,
+            nonce: self.nonce,
+        }
+    }
+
     pub fn generate_hash(&mut self) -> String {
-        let block_string = serde_json::to_string(&self);
+        self.hash = self.calculate_hash();
+        self.hash.clone()
+    }
 
-        let hashed = Sha256::new().chain_update(block_string.unwrap()).finalize();
+    pub fn calculate_hash(&self) -> String {
+        self.header().calculate_hash()
+    }
 
-        self.hash = format!("{:x}", hashed);
-        self.hash.clone()
+    pub fn has_valid_hash(&self) -> bool {
+        self.hash == self.calculate_hash()
+    }
+
+    /// Whether this block is the body belonging to `header`.
+    pub fn matches_header(&self, header: &BlockHeader) -> bool {
+        self.has_valid_hash() && self.header() == *header
+    }
+
+    /// Checks what can be checked without the chain: size, hash, proof of work and transactions.
+    pub fn validate(&self, difficulty: usize) -> Result<(), ValidationError> {
+        let size = encoded_size(self);
+        if size > MAX_BLOCK_SIZE {
+            return Err(ValidationError::TooLarge { size, max: MAX_BLOCK_SIZE });
+        }
+        if !self.has_valid_hash() {
+            return Err(ValidationError::InvalidHash);
+        }
+        if !self.header().meets_difficulty(difficulty) {
+            return Err(ValidationError::InsufficientWork { difficulty });
+        }
+        for (index, tx) in self.transactions.iter().enumerate() {
+            tx.validate()
+                .map_err(|error| ValidationError::InvalidTransaction { index, error })?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_parent(&self, prev_block: &Block) -> Result<(), ValidationError> {
+        if self.prev_hash != prev_block.hash {
+            return Err(ValidationError::UnknownParent(self.prev_hash.clone()));
+        }
+        Ok(())
     }
 
     pub fn is_valid(&self, prev_block: &Block) -> bool {
-        self.prev_hash == prev_block.hash
+        self.validate_parent(prev_block).is_ok()
     }
 }
 
@@ -54,7 +152,7 @@ impl fmt::Display for Block {
         result_string
             .push_str(&("Nonce: ".to_owned() + &self.nonce.to_string() + "\r\n"));
         result_string.push_str(
-            &("Time: ".to_owned() + &self.time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64().to_string() + "\r\n"),
+            &("Time: ".to_owned() + &self.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64().to_string() + "\r\n"),
         );
         result_string.push_str(&("=".repeat(30) + "\r\r\n\n"));
 
@@ -64,7 +162,8 @@ impl fmt::Display for Block {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::{block::Block, transaction::Transaction};
+    use crate::error::{TransactionError, ValidationError};
+    use crate::{block::{Block, MAX_BLOCK_SIZE}, blockchain::Blockchain, transaction::Transaction};
     use std::time::SystemTime;
 
     pub fn generate_blocks() -> Vec<Block> {
@@ -158,4 +257,61 @@ pub mod tests {
             second_block_txs_differ.clone().generate_hash()
         );
     }
+
+    #[test]
+    fn test_header() {
+        let mut block = generate_blocks()[0].clone();
+        block.generate_hash();
+
+        let header = block.header();
+        assert!(header.has_valid_hash());
+        assert!(block.matches_header(&header));
+
+        let mut other = block.clone();
+        other.transactions[0].amount += 1;
+        assert!(!other.has_valid_hash());
+        assert!(!other.matches_header(&header));
+
+        let mut zeros = header;
+        zeros.hash = String::from("00a1");
+        assert!(zeros.meets_difficulty(2));
+        assert!(!zeros.meets_difficulty(3));
+        assert_eq!(zeros.work(), 256);
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let mut chain = Blockchain::new(0, 1, 256);
+        chain.try_mine(vec![]).unwrap();
+        chain.try_mine(generate_blocks()[0].transactions.clone()).unwrap();
+        let (parent, block) = (chain.chain[0].clone(), chain.chain[1].clone());
+        assert_eq!(block.validate(1), Ok(()));
+        assert_eq!(block.validate_parent(&parent), Ok(()));
+
+        assert_eq!(block.validate(64), Err(ValidationError::InsufficientWork { difficulty: 64 }));
+        assert_eq!(
+            parent.validate_parent(&block),
+            Err(ValidationError::UnknownParent(parent.prev_hash.clone()))
+        );
+
+        let mut tampered = block.clone();
+        tampered.nonce += 1;
+        assert_eq!(tampered.validate(1), Err(ValidationError::InvalidHash));
+
+        let mut self_transfer = block;
+        self_transfer.transactions[0].to = self_transfer.transactions[0].from.clone();
+        self_transfer.generate_hash();
+        assert!(matches!(
+            self_transfer.validate(0),
+            Err(ValidationError::InvalidTransaction {
+                index: 0,
+                error: TransactionError::SelfTransfer
+            })
+        ));
+
+        let mut oversized = chain.chain[1].clone();
+        oversized.transactions[0].from = "a".repeat(MAX_BLOCK_SIZE);
+        oversized.generate_hash();
+        assert!(matches!(oversized.validate(0), Err(ValidationError::TooLarge { .. })));
+    }
 }
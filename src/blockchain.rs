@@ -1,5 +1,8 @@
-use crate::{block::Block, transaction::Transaction};
-use rand::Rng;
+use crate::{
+    block::{calculate_tx_root, Block, BlockHeader},
+    error::ValidationError,
+    transaction::Transaction,
+};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
@@ -28,96 +31,195 @@ impl Blockchain {
         self.chain.len()
     }
 
-    pub fn add_block(&mut self, block: Block) {
-        if self.chain.len() == 0 {
-            return;
-        }
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
 
-        let latest_block = self.chain.last().unwrap();
-        if block.is_valid(latest_block) {
-            self.chain.push(block);
-        } 
-        // Here in else we should add this block to orphans, but we will not do it
+    pub fn difficulty(&self) -> usize {
+        self.difficulty
     }
 
-    pub fn is_valid(&self) -> bool {
-        for i in 1..self.chain.len() {
-            if !(self
-                .chain
-                .get(i)
-                .unwrap()
-                .is_valid(self.chain.get(i - 1).unwrap()))
-            {
-                return false;
+    pub fn total_work(&self) -> u128 {
+        self.work_since(0)
+    }
+
+    /// Work of the blocks from `height` up to the tip.
+    pub fn work_since(&self, height: usize) -> u128 {
+        self.chain
+            .iter()
+            .skip(height)
+            .map(|block| block.header().work())
+            .sum()
+    }
+
+    pub fn height_of(&self, hash: &str) -> Option<usize> {
+        self.chain.iter().rposition(|block| block.hash == hash)
+    }
+
+    /// Coins `address` received minus what it sent in confirmed transactions.
+    /// There are no block rewards, so this can be negative.
+    pub fn balance(&self, address: &str) -> i64 {
+        self.chain
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .map(|tx| match (tx.from == address, tx.to == address) {
+                (true, false) => -i64::from(tx.amount),
+                (false, true) => i64::from(tx.amount),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Hashes describing our chain to a peer: the last 10 blocks one by one,
+    /// then exponentially further back, always ending with the first block.
+    pub fn locator(&self) -> Vec<String> {
+        let mut locator = vec![];
+        if self.chain.is_empty() {
+            return locator;
+        }
+
+        let mut height = self.chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.chain[height].hash.clone());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
             }
+            height = height.saturating_sub(step);
         }
+        locator
+    }
+
+    /// Headers following the first block of `locator` we know about, at most `max` of them.
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .map_or(0, |height| height + 1);
+
+        self.chain
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|block| block.header())
+            .collect()
+    }
+
+    pub fn blocks_by_hash(&self, hashes: &[String]) -> Vec<Block> {
+        hashes
+            .iter()
+            .filter_map(|hash| self.height_of(hash).map(|height| self.chain[height].clone()))
+            .collect()
+    }
+
+    /// Copy of this chain with every block from `height` on replaced by `blocks`.
+    pub fn fork_at(&self, height: usize, blocks: Vec<Block>) -> Blockchain {
+        let mut forked = self.clone();
+        forked.chain.truncate(height);
+        forked.chain.extend(blocks);
+        forked
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        let latest_block = self.chain.last().ok_or(ValidationError::EmptyChain)?;
+        // Here on error we should add this block to orphans, but we will not do it
+        block.validate_parent(latest_block)?;
+        self.chain.push(block);
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let valid = self.valid_prefix_len();
+        if valid < self.chain.len() {
+            return Err(ValidationError::BrokenLink { height: valid });
+        }
+        Ok(())
+    }
 
-        true
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Number of blocks from the start which link up correctly.
+    pub fn valid_prefix_len(&self) -> usize {
+        self.chain
+            .windows(2)
+            .position(|pair| !pair[1].is_valid(&pair[0]))
+            .map_or(self.chain.len(), |broken| broken + 1)
     }
 
-    pub fn try_mine(&mut self, txs: Vec<Transaction>) -> bool {
-        let success;
+    /// Mines a block of `txs` on top of the chain. Returns its hash.
+    pub fn try_mine(&mut self, txs: Vec<Transaction>) -> Result<String, ValidationError> {
+        let block = self.mining_job(txs)?.mine(|| false).expect("mining only stops when cancelled");
+        let hash = block.hash.clone();
+        self.chain.push(block);
+        Ok(hash)
+    }
+
+    /// The work of mining a block of `txs` on top of the chain, to be done elsewhere.
+    pub fn mining_job(&self, txs: Vec<Transaction>) -> Result<MiningJob, ValidationError> {
         if txs.len() < self.min_tx_per_block.into() {
-            println!(
-                "Not enough txs to mine block. Current txs {}, Current min is {}",
-                txs.len(),
-                self.min_tx_per_block
-            );
-            success = false;
-        } else {
-            let mut nonce = 0;
-            loop {
-                let time = SystemTime::now();
-
-                let block = self.mine_block(nonce, time, txs.clone());
-                match block {
-                    Some(block) => {
-                        self.chain.push(block);
-                        success = true;
-                        break;
-                    }
-                    None => {}
-                };
-
-                nonce += self.concurrent_hashes;
+            return Err(ValidationError::TooFewTransactions {
+                found: txs.len(),
+                min: self.min_tx_per_block.into(),
+            });
+        }
+        Ok(MiningJob {
+            prev_hash: self.chain.last().map(|last| last.hash.clone()).unwrap_or_default(),
+            tx_root: calculate_tx_root(&txs),
+            txs,
+            difficulty: self.difficulty,
+            concurrent_hashes: self.concurrent_hashes,
+        })
+    }
+}
+
+// Everything needed to mine a block, so it can be mined away from the chain it goes on
+#[derive(Debug, Clone, PartialEq)]
+pub struct MiningJob {
+    pub prev_hash: String,
+    pub txs: Vec<Transaction>,
+    tx_root: String,
+    difficulty: usize,
+    concurrent_hashes: u64,
+}
+
+impl MiningJob {
+    /// Tries nonces in rounds of `concurrent_hashes` until a block is found,
+    /// or `cancelled` says to stop. It is asked between rounds.
+    pub fn mine<F: Fn() -> bool>(&self, cancelled: F) -> Option<Block> {
+        let mut nonce = 0;
+        while !cancelled() {
+            if let Some(block) = self.mine_round(nonce, SystemTime::now()) {
+                return Some(block);
             }
+            nonce += self.concurrent_hashes;
         }
-        success
-    }
-
-    fn mine_block(
-        &self,
-        nonce: u64,
-        time: SystemTime,
-        txs: Vec<Transaction>
-    ) -> Option<Block> {
-        const CHARSET: &[u8] = b"abcdef\
-                            0123456789";
-        let mut rng = rand::thread_rng();
-
-        let mut mine_target: String = (0..self.difficulty)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
+        None
+    }
 
-        mine_target = mine_target.to_lowercase();
+    fn mine_round(&self, nonce: u64, time: SystemTime) -> Option<Block> {
+        let mine_target = "0".repeat(self.difficulty);
 
         let nonces: Vec<u64> = (0..self.concurrent_hashes).map(|x| x + nonce).collect();
 
-        let prev = match self.chain.len() {
-            0 => String::new(),
-            _ => self.chain.get(self.chain.len() - 1).unwrap().hash.clone(),
-        };
-
-        nonces.par_iter().find_map_any(move |&nonce| {
-            let mut block = Block::new(prev.clone(), txs.clone(), nonce, time);
+        nonces.par_iter().find_map_any(|&nonce| {
+            let mut header = BlockHeader {
+                hash: String::new(),
+                prev_hash: self.prev_hash.clone(),
+                tx_root: self.tx_root.clone(),
+                time,
+                nonce,
+            };
 
-            let hash = block.generate_hash();
+            header.hash = header.calculate_hash();
 
-            if hash.starts_with(&mine_target) {
-                println!("\nMined! {}\n", block.hash.clone());
+            if header.hash.starts_with(&mine_target) {
+                let mut block = Block::new(self.prev_hash.clone(), self.txs.clone(), nonce, time);
+                block.hash = header.hash;
                 return Some(block);
             }
 
@@ -140,10 +242,10 @@ impl fmt::Display for Blockchain {
             display_chain.push_str(&("-".repeat(15) + "\r\n"));
             display_chain.push_str(&self.chain[i].to_string());
         }
-        if self.chain.len() == 0 {
+        if self.chain.is_empty() {
             display_chain.push_str("Chain is empty for now. Try to generate few transactions");
         }
-        write!(f, "{}\n", display_chain)
+        writeln!(f, "{}", display_chain)
     }
 }
 
@@ -155,14 +257,12 @@ pub mod tests {
 
     pub fn generate_blockchain() -> Blockchain {
         let blocks = generate_blocks();
-        let chain = Blockchain {
+        Blockchain {
             chain: blocks,
             difficulty: 5,
             min_tx_per_block: 3,
             concurrent_hashes: 256,
-        };
-
-        chain
+        }
     }
 
     #[test]
@@ -177,6 +277,21 @@ pub mod tests {
         };
 
         assert!(chain.is_valid());
+
+        let mut broken = chain.clone();
+        broken.chain.extend(crate::block::tests::generate_blocks());
+        assert!(!broken.is_valid());
+        assert_eq!(broken.valid_prefix_len(), chain.len());
+    }
+
+    #[test]
+    fn test_balance() {
+        // Every generated block has Alice send 32 to Bob
+        let chain = generate_blockchain();
+        let sent = 32 * chain.len() as i64;
+        assert_eq!(chain.balance("Alice"), -sent);
+        assert_eq!(chain.balance("Bob"), sent);
+        assert_eq!(chain.balance("Carol"), 0);
     }
 
     #[test]
@@ -198,7 +313,7 @@ pub mod tests {
         for i in 0..10 {
             txs.push(Transaction {
                 from: String::from("test"),
-                to: String::from(i.to_string()),
+                to: i.to_string(),
                 amount: i,
                 time: SystemTime::now(),
             });
@@ -206,6 +321,7 @@ pub mod tests {
 
         let concurrent_hashes = 256;
         let chain = Blockchain::new(5, 3, concurrent_hashes);
+        let job = chain.mining_job(txs.clone()).unwrap();
         let mut _nonce = 0;
         let time = SystemTime::now();
 
@@ -213,11 +329,37 @@ pub mod tests {
         loop {
             cntr += 1;
 
-            chain.mine_block(1, time, txs.clone());
+            job.mine_round(1, time);
             _nonce += concurrent_hashes;
             if cntr == 100 {
                 break;
             }
         }
+
+        assert_eq!(job.mine(|| true), None);
+        assert!(chain.mining_job(txs[..2].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut chain = generate_blockchain();
+        let block = chain.chain[0].clone();
+        chain.chain.clear();
+        for i in 0..33 {
+            let mut next = block.clone();
+            next.hash = i.to_string();
+            chain.chain.push(next);
+        }
+
+        let locator = chain.locator();
+        let heights: Vec<usize> = locator.iter().map(|h| chain.height_of(h).unwrap()).collect();
+        assert_eq!(heights[..10], [32, 31, 30, 29, 28, 27, 26, 25, 24, 23]);
+        assert_eq!(heights[10..], [21, 17, 9, 0]);
+
+        let headers = chain.headers_after(&[String::from("unknown"), String::from("27")], 2);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].hash, "28");
+        assert_eq!(headers[1].hash, "29");
+        assert_eq!(chain.headers_after(&[], 100).len(), chain.len());
     }
 }
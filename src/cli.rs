@@ -0,0 +1,430 @@
+use libp2p::{futures::StreamExt, identity::ed25519, PeerId, Swarm};
+use serde_json::{json, Value};
+use log::LevelFilter;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::select;
+
+use crate::{
+    config::Config,
+    daemon,
+    discovery::NetworkConfig,
+    error::NodeError,
+    keyfile,
+    logger::LogFormat,
+    p2p::{self, AppBehaviour},
+    rpc,
+    sync::SYNC_REQUEST_TIMEOUT,
+    transaction::Transaction,
+};
+
+pub const USAGE: &str = "usage: elemchain [<command>] [--json] [--wait <secs>] [<node options>] [<network options>]
+
+commands:
+  (none)                              run the node with the interactive menu
+  node run [--menu]                   run the node until it is stopped
+  wallet new                          create a new wallet key in the key file
+  wallet balance [--address <id>]     balance of the key file's or another wallet
+  tx send --to <id> --amount <n>      send coins from the key file's wallet
+  chain show [--height <n>]           print the whole chain or one block
+  mine [--blocks <n>]                 mine pending transactions into n blocks (default 1)
+  peers list                          list the peers found while syncing
+
+node options:
+  --data-dir <path> --config <path> --log-level error|warn|info|debug|trace --log-format text|json
+  --[no-]mine --concurrent-hashes <n> --max-pending-txs <n> --chain-file <path> --pid-file <path>
+  --[no-]rpc --rpc-bind <addr> --[no-]rest --rest-bind <addr> --[no-]ws --ws-bind <addr>
+  --[no-]metrics --metrics-bind <addr>
+
+network options:
+  --network main|test --listen <multiaddr> --bootstrap <multiaddr>... --[no-]mdns
+  --key-file <path> --peers-file <path> --ephemeral --[no-]dandelion";
+
+/// How long one-shot commands sync with the network before doing their work.
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+/// Time given to gossip to get our blocks and transactions out before exiting.
+pub const FLUSH_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Runs the node until it is stopped, driven by the menu if `menu` is set.
+    Run { menu: bool },
+    WalletNew,
+    WalletBalance { address: Option<String> },
+    TxSend { to: String, amount: i32 },
+    ChainShow { height: Option<usize> },
+    Mine { blocks: usize },
+    PeersList,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    pub config: Config,
+    pub wait: Duration,
+    /// Print results as a single line of JSON.
+    pub json: bool,
+    /// `None` for the default of the command.
+    pub log_level: Option<LevelFilter>,
+    pub log_format: LogFormat,
+}
+
+impl Cli {
+    /// Reads the command words, then its options. Whatever is left goes to `Config::load`
+    /// together with the environment `vars`. Without a command the node runs with the menu.
+    pub fn from_args<I, E>(args: I, vars: E) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+        E: Iterator<Item = (String, String)>,
+    {
+        let mut args: Vec<String> = args.collect();
+        let command_len = args.iter().take_while(|arg| !arg.starts_with("--")).count();
+        let words: Vec<String> = args.drain(..command_len).collect();
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let mut command = match words.as_slice() {
+            [] => Command::Run { menu: true },
+            ["node", "run"] => Command::Run { menu: false },
+            ["wallet", "new"] => Command::WalletNew,
+            ["wallet", "balance"] => Command::WalletBalance { address: None },
+            ["tx", "send"] => Command::TxSend {
+                to: String::new(),
+                amount: 0,
+            },
+            ["chain", "show"] => Command::ChainShow { height: None },
+            ["mine"] => Command::Mine { blocks: 1 },
+            ["peers", "list"] => Command::PeersList,
+            _ => return Err(format!("unknown command {}", words.join(" "))),
+        };
+
+        let mut wait = DEFAULT_WAIT;
+        let mut json = false;
+        let mut log_level = None;
+        let mut log_format = LogFormat::Text;
+        let mut config_args = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match (arg.as_str(), &mut command) {
+                ("--json", _) => json = true,
+                ("--wait", _) => wait = Duration::from_secs(parse_value(&arg, args.next())?),
+                ("--log-level", _) => log_level = Some(parse_value(&arg, args.next())?),
+                ("--log-format", _) => log_format = parse_value(&arg, args.next())?,
+                ("--menu", Command::Run { menu }) => *menu = true,
+                ("--address", Command::WalletBalance { address }) => *address = Some(parse_value(&arg, args.next())?),
+                ("--to", Command::TxSend { to, .. }) => *to = parse_value(&arg, args.next())?,
+                ("--amount", Command::TxSend { amount, .. }) => *amount = parse_value(&arg, args.next())?,
+                ("--height", Command::ChainShow { height }) => *height = Some(parse_value(&arg, args.next())?),
+                ("--blocks", Command::Mine { blocks }) => *blocks = parse_value(&arg, args.next())?,
+                _ => config_args.push(arg),
+            }
+        }
+        if let Command::TxSend { to, .. } = &command {
+            if to.is_empty() {
+                return Err(String::from("tx send expects --to and --amount"));
+            }
+        }
+
+        Ok(Cli {
+            command,
+            config: Config::load(config_args.into_iter(), vars)?,
+            wait,
+            json,
+            log_level,
+            log_format,
+        })
+    }
+
+    /// Nodes report what they do, one-shot commands only what went wrong.
+    pub fn log_level(&self) -> LevelFilter {
+        match (self.log_level, &self.command) {
+            (Some(level), _) => level,
+            (None, Command::Run { .. }) => LevelFilter::Info,
+            (None, _) => LevelFilter::Warn,
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} expects a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+/// What a command prints, as text for people or as JSON for scripts.
+#[derive(Debug)]
+pub struct Output {
+    pub text: String,
+    pub json: Value,
+}
+
+impl Output {
+    pub fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.json);
+        } else {
+            println!("{}", self.text);
+        }
+    }
+}
+
+/// Runs a one-shot command: starts a node, syncs with the network for `cli.wait`,
+/// does the work and gives gossip a moment before returning.
+pub async fn execute(cli: &Cli) -> Result<Output, NodeError> {
+    let network = &cli.config.network;
+    let wallet = match &cli.command {
+        Command::WalletNew => return wallet_new(network),
+        Command::WalletBalance { address: Some(address) } => address.clone(),
+        Command::WalletBalance { address: None } | Command::TxSend { .. } => wallet_address(network)?,
+        _ => String::new(),
+    };
+
+    // A throwaway identity and port, so commands can run next to a node using the same files
+    let session_config = NetworkConfig {
+        key_file: None,
+        listen_addrs: NetworkConfig::default().listen_addrs,
+        ..network.clone()
+    };
+    // Starts from the chain a node on these files stored, but never writes it
+    let mut swarm = p2p::start_swarm(daemon::open_node(&cli.config)?, &session_config).await?;
+    sync_with_network(&mut swarm, cli.wait).await;
+
+    let output = match &cli.command {
+        Command::WalletBalance { .. } => {
+            let chain = &swarm.behaviour().node.blockchain;
+            let balance = chain.balance(&wallet);
+            Output {
+                text: format!("{} has {} at height {}", wallet, balance, chain.len() - 1),
+                json: json!({ "address": wallet, "balance": balance, "height": chain.len() - 1 }),
+            }
+        }
+        Command::TxSend { to, amount } => send_transaction(&mut swarm, wallet, to.clone(), *amount)?,
+        Command::ChainShow { height } => show_chain(&swarm, *height)?,
+        Command::Mine { blocks } => mine(&mut swarm, *blocks).await?,
+        Command::PeersList => list_peers(&swarm),
+        Command::Run { .. } | Command::WalletNew => unreachable!("not a one-shot command"),
+    };
+
+    p2p::run_for(&mut swarm, FLUSH_TIME).await;
+    Ok(output)
+}
+
+/// Drives the swarm for `wait`, then until a running sync round is done or times out.
+async fn sync_with_network(swarm: &mut Swarm<AppBehaviour>, wait: Duration) {
+    p2p::run_for(swarm, wait).await;
+    let deadline = tokio::time::Instant::now() + SYNC_REQUEST_TIMEOUT;
+    while !swarm.behaviour().sync.is_idle() && tokio::time::Instant::now() < deadline {
+        p2p::run_for(swarm, Duration::from_millis(100)).await;
+    }
+}
+
+fn key_file(config: &NetworkConfig) -> Result<&Path, NodeError> {
+    config
+        .key_file
+        .as_deref()
+        .ok_or_else(|| NodeError::Command(String::from("wallet commands need a key file, drop --ephemeral")))
+}
+
+/// The wallet address is the peer id of the key in the key file.
+fn wallet_address(config: &NetworkConfig) -> Result<String, NodeError> {
+    let path = key_file(config)?;
+    let keypair = keyfile::load_keypair(path).map_err(|error| NodeError::KeyFile {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    Ok(PeerId::from(keypair.public()).to_string())
+}
+
+fn wallet_new(config: &NetworkConfig) -> Result<Output, NodeError> {
+    let path = key_file(config)?;
+    let keypair = ed25519::Keypair::generate();
+    keyfile::save_keypair(&keypair, path).map_err(|error| NodeError::KeyFile {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let address = PeerId::from(libp2p::identity::PublicKey::Ed25519(keypair.public())).to_string();
+    Ok(Output {
+        text: format!("Created wallet {} in {}", address, path.display()),
+        json: json!({ "address": address, "key_file": path }),
+    })
+}
+
+fn send_transaction(swarm: &mut Swarm<AppBehaviour>, from: String, to: String, amount: i32) -> Result<Output, NodeError> {
+    let tx = Transaction {
+        from,
+        to,
+        amount,
+        time: SystemTime::now(),
+    };
+    tx.validate()
+        .map_err(|e| NodeError::Command(format!("invalid transaction: {}", e)))?;
+    let peers = swarm.behaviour().connected_peers().len();
+    if peers == 0 {
+        return Err(NodeError::Command(String::from("no peers to send the transaction to")));
+    }
+    swarm.behaviour_mut().submit_transaction(tx.clone());
+    Ok(Output {
+        text: format!("Sent tx {}\n{}", tx.id(), tx),
+        json: json!({ "id": tx.id(), "from": tx.from, "to": tx.to, "amount": tx.amount, "peers": peers }),
+    })
+}
+
+fn show_chain(swarm: &Swarm<AppBehaviour>, height: Option<usize>) -> Result<Output, NodeError> {
+    let chain = &swarm.behaviour().node.blockchain;
+    let height = match height {
+        Some(height) => height,
+        None => {
+            return Ok(Output {
+                text: chain.to_string(),
+                json: json!({ "height": chain.len() - 1, "blocks": chain.chain }),
+            })
+        }
+    };
+    match chain.chain.get(height) {
+        Some(block) => Ok(Output {
+            text: block.to_string(),
+            json: json!({ "height": height, "block": block }),
+        }),
+        None => Err(NodeError::Command(format!(
+            "no block at height {}, the chain has {} blocks",
+            height,
+            chain.len()
+        ))),
+    }
+}
+
+/// Mines on the background miner, driving the swarm until it is done.
+async fn mine(swarm: &mut Swarm<AppBehaviour>, blocks: usize) -> Result<Output, NodeError> {
+    // The session never writes its chain, so blocks no peer picked up would be lost
+    if swarm.behaviour().connected_peers().is_empty() {
+        return Err(NodeError::Command(String::from("no peers to send the mined blocks to")));
+    }
+    let mut mined = swarm.behaviour_mut().generate(blocks as u64)?;
+    let mined = loop {
+        select! {
+            mined = &mut mined => break mined,
+            event = swarm.select_next_some() => {
+                p2p::handle_swarm_event(swarm, event);
+            }
+        }
+    }
+    .map_err(|_| NodeError::Command(String::from("mining stopped before all blocks were mined")))?;
+    Ok(Output {
+        text: mined
+            .iter()
+            .map(|(height, hash)| format!("Mined block {} at height {}", hash, height))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        json: json!({
+            "blocks": mined
+                .iter()
+                .map(|(height, hash)| json!({ "height": height, "hash": hash }))
+                .collect::<Vec<_>>()
+        }),
+    })
+}
+
+fn list_peers(swarm: &Swarm<AppBehaviour>) -> Output {
+    let peers = rpc::peer_info(swarm.behaviour());
+    let list = peers.as_array().expect("peer info is a list");
+    let mut text = format!("{} peers", list.len());
+    for peer in list {
+        text.push_str(&format!(
+            "\n{} height {} {}",
+            peer["peer_id"].as_str().unwrap_or_default(),
+            peer["height"],
+            peer["user_agent"].as_str().unwrap_or_default()
+        ));
+    }
+    Output {
+        text,
+        json: json!({ "peers": peers }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{execute, Cli, Command, DEFAULT_WAIT};
+    use crate::config::Config;
+    use crate::discovery::tests::local_config;
+    use crate::logger::LogFormat;
+    use log::LevelFilter;
+    use std::iter;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn parse(args: &str) -> Result<Cli, String> {
+        Cli::from_args(args.split_whitespace().map(String::from), iter::empty())
+    }
+
+    #[test]
+    fn test_args() {
+        // Plain network options keep running the menu, as before there were commands
+        let cli = parse("--network test --no-mdns").unwrap();
+        assert_eq!(cli.command, Command::Run { menu: true });
+        assert_eq!(cli.config.network.network_id, "test");
+        assert!(!cli.config.network.mdns);
+
+        let cli = parse("tx send --to bob --json --amount 5 --ephemeral --wait 2").unwrap();
+        assert_eq!(
+            cli.command,
+            Command::TxSend {
+                to: String::from("bob"),
+                amount: 5
+            }
+        );
+        assert!(cli.json);
+        assert_eq!(cli.wait, Duration::from_secs(2));
+        assert_eq!(cli.config.network.key_file, None);
+
+        let cli = parse("mine --blocks 3").unwrap();
+        assert_eq!(cli.command, Command::Mine { blocks: 3 });
+        assert_eq!(cli.wait, DEFAULT_WAIT);
+        assert_eq!(parse("chain show --height 2").unwrap().command, Command::ChainShow { height: Some(2) });
+        assert_eq!(parse("node run").unwrap().command, Command::Run { menu: false });
+        assert_eq!(cli.log_level(), LevelFilter::Warn);
+
+        let cli = parse("node run --log-format json --pid-file /tmp/node.pid").unwrap();
+        assert_eq!(cli.log_level(), LevelFilter::Info);
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert_eq!(cli.config.pid_file, Some(PathBuf::from("/tmp/node.pid")));
+        assert_eq!(parse("node run --log-level debug").unwrap().log_level(), LevelFilter::Debug);
+
+        assert!(parse("tx send --amount 5").is_err());
+        assert!(parse("mine --blocks many").is_err());
+        // Options belong to their command
+        assert!(parse("mine --height 2").is_err());
+        assert!(parse("wallet burn").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chain_show() {
+        let cli = Cli {
+            command: Command::ChainShow { height: Some(0) },
+            config: Config {
+                network: local_config(),
+                chain_file: None,
+                ..Config::new("")
+            },
+            wait: Duration::ZERO,
+            json: true,
+            log_level: None,
+            log_format: LogFormat::Text,
+        };
+        let output = execute(&cli).await.unwrap();
+        assert_eq!(output.json["height"], 0);
+        assert_eq!(output.json["block"]["prev_hash"], "");
+
+        let cli = Cli {
+            command: Command::ChainShow { height: Some(1) },
+            ..cli
+        };
+        assert!(execute(&cli).await.is_err());
+
+        let cli = Cli {
+            command: Command::Mine { blocks: 2 },
+            ..cli
+        };
+        assert!(execute(&cli).await.is_err());
+    }
+}
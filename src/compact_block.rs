@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::{
+    block::{Block, BlockHeader},
+    transaction::Transaction,
+};
+
+/// Hex digits kept of a short transaction id.
+pub const SHORT_ID_LEN: usize = 16;
+/// Blocks waiting for missing transactions at once, the oldest is dropped beyond that.
+pub const MAX_PARTIAL_BLOCKS: usize = 8;
+
+/// Id of a transaction within one block. Salting with the block hash means a collision
+/// crafted against one block does not carry over to the next.
+pub fn short_id(block_hash: &str, tx_id: &str) -> String {
+    let hashed = Sha256::new()
+        .chain_update(block_hash)
+        .chain_update(tx_id)
+        .finalize();
+    format!("{:x}", hashed)[..SHORT_ID_LEN].to_string()
+}
+
+// What a miner gossips instead of the full block. Peers usually have the transactions
+// in their pending pool already and only ask for the ones they miss.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub height: usize,
+    pub short_ids: Vec<String>,
+}
+
+impl CompactBlock {
+    pub fn from_block(block: &Block, height: usize) -> Self {
+        CompactBlock {
+            header: block.header(),
+            height,
+            short_ids: block
+                .transactions
+                .iter()
+                .map(|tx| short_id(&block.hash, &tx.id()))
+                .collect(),
+        }
+    }
+
+    /// Matches the short ids against `pool`, leaving gaps for the transactions we don't have.
+    pub fn fill_from<'a, I>(self, pool: I) -> PartialBlock
+    where
+        I: IntoIterator<Item = &'a Transaction>,
+    {
+        let wanted: HashMap<&str, usize> = self
+            .short_ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (id.as_str(), index))
+            .collect();
+        let mut txs = vec![None; self.short_ids.len()];
+        for tx in pool {
+            if let Some(index) = wanted.get(short_id(&self.header.hash, &tx.id()).as_str()) {
+                txs[*index] = Some(tx.clone());
+            }
+        }
+        PartialBlock { compact: self, txs }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub compact: CompactBlock,
+    txs: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Positions of the transactions still missing, in block order.
+    pub fn missing(&self) -> Vec<usize> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Fills the gaps with `txs`, given in the order of `missing`.
+    /// Returns false if they don't match the gaps.
+    pub fn fill_missing(&mut self, txs: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return false;
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            if short_id(&self.compact.header.hash, &tx.id()) != self.compact.short_ids[index] {
+                return false;
+            }
+            self.txs[index] = Some(tx);
+        }
+        true
+    }
+
+    /// The full block once nothing is missing. Its hash still has to be checked,
+    /// a short id collision gives a block whose transactions don't match the header.
+    pub fn to_block(&self) -> Option<Block> {
+        let txs = self.txs.iter().cloned().collect::<Option<Vec<Transaction>>>()?;
+        let header = &self.compact.header;
+        let mut block = Block::new(header.prev_hash.clone(), txs, header.nonce, header.time);
+        block.hash = header.hash.clone();
+        Some(block)
+    }
+}
+
+// Blocks being reconstructed, by hash
+#[derive(Debug, Default)]
+pub struct PartialBlocks {
+    blocks: HashMap<String, PartialBlock>,
+}
+
+impl PartialBlocks {
+    pub fn insert(&mut self, partial: PartialBlock) {
+        if self.blocks.len() >= MAX_PARTIAL_BLOCKS {
+            let oldest = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, partial)| partial.compact.height)
+                .map(|(hash, _)| hash.clone());
+            if let Some(hash) = oldest {
+                self.blocks.remove(&hash);
+            }
+        }
+        self.blocks.insert(partial.compact.header.hash.clone(), partial);
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn remove(&mut self, hash: &str) -> Option<PartialBlock> {
+        self.blocks.remove(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::Blockchain;
+    use crate::block::tests::generate_blocks;
+    use crate::compact_block::{CompactBlock, PartialBlocks, MAX_PARTIAL_BLOCKS};
+
+    #[test]
+    fn test_reconstruct() {
+        let mut txs = generate_blocks()[0].transactions.clone();
+        let mut other = txs[0].clone();
+        other.amount += 1;
+        txs.push(other);
+        let mut chain = Blockchain::new(0, 1, 256);
+        chain.try_mine(txs.clone()).unwrap();
+        let block = chain.chain.last().unwrap().clone();
+
+        let compact = CompactBlock::from_block(&block, 1);
+        assert_eq!(compact.short_ids.len(), txs.len());
+
+        // Everything known already
+        let partial = compact.clone().fill_from(&txs);
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.to_block(), Some(block.clone()));
+
+        // The last one has to be asked for
+        let mut partial = compact.fill_from(&txs[..txs.len() - 1]);
+        assert_eq!(partial.missing(), vec![txs.len() - 1]);
+        assert_eq!(partial.to_block(), None);
+        assert!(!partial.fill_missing(vec![txs[0].clone()]));
+        assert!(partial.fill_missing(vec![txs[txs.len() - 1].clone()]));
+        let rebuilt = partial.to_block().unwrap();
+        assert_eq!(rebuilt, block);
+        assert!(rebuilt.has_valid_hash());
+    }
+
+    #[test]
+    fn test_partial_blocks_are_bounded() {
+        let block = generate_blocks().remove(0);
+        let mut partials = PartialBlocks::default();
+        for height in 0..=MAX_PARTIAL_BLOCKS {
+            let mut compact = CompactBlock::from_block(&block, height);
+            compact.header.hash = height.to_string();
+            partials.insert(compact.fill_from(&[]));
+        }
+        assert!(!partials.contains("0"));
+        assert!(partials.contains(&MAX_PARTIAL_BLOCKS.to_string()));
+        assert!(partials.remove("1").is_some());
+    }
+}
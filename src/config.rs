@@ -0,0 +1,484 @@
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    address_book::DEFAULT_ADDRESS_BOOK,
+    daemon::DEFAULT_PID_FILE,
+    discovery::NetworkConfig,
+    keyfile::DEFAULT_KEY_FILE,
+    metrics::DEFAULT_METRICS_ADDR,
+    node::DEFAULT_MAX_PENDING_TXS,
+    rest::DEFAULT_REST_ADDR,
+    rpc::DEFAULT_RPC_ADDR,
+    ws::DEFAULT_WS_ADDR,
+};
+
+// Data directory layout. Relative paths in the config file are relative to the data
+// directory, relative paths on the command line or in the environment to the working directory.
+//
+//   config.toml       optional, `config.json` works as well
+//   node.key          node identity, its peer id is also the wallet address
+//   peers.json        known peers
+//   chain/<network>   the chain, as an archive
+//   elemchain.pid     locked while a node runs
+pub const DEFAULT_DATA_DIR: &str = ".elemchain";
+pub const CONFIG_FILE: &str = "config.toml";
+pub const CHAIN_DIR: &str = "chain";
+pub const DEFAULT_CONCURRENT_HASHES: u64 = 256;
+/// Prefix of the environment variables overriding the config file, e.g. `ELEMCHAIN_NETWORK`.
+pub const ENV_PREFIX: &str = "ELEMCHAIN_";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub network: NetworkConfig,
+    /// Hashes tried in parallel per mining round.
+    pub concurrent_hashes: u64,
+    /// Mine on a background worker from the start, until stopped.
+    pub mine: bool,
+    pub max_pending_txs: usize,
+    /// Where the chain is kept between runs, `None` to start from genesis every time.
+    pub chain_file: Option<PathBuf>,
+    pub pid_file: Option<PathBuf>,
+    /// Where the JSON-RPC server listens, `None` to not start it.
+    pub rpc_addr: Option<SocketAddr>,
+    /// Where the REST API listens, `None` to not start it.
+    pub rest_addr: Option<SocketAddr>,
+    /// Where WebSocket subscribers connect, `None` to not start the server.
+    pub ws_addr: Option<SocketAddr>,
+    /// Where Prometheus scrapes `/metrics`, `None` to not start the server.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    network: Option<String>,
+    listen: Vec<String>,
+    bootstrap: Vec<String>,
+    mdns: Option<bool>,
+    dandelion: Option<bool>,
+    mining: MiningSection,
+    mempool: MempoolSection,
+    storage: StorageSection,
+    rpc: ServerSection,
+    rest: ServerSection,
+    ws: ServerSection,
+    metrics: ServerSection,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct MiningSection {
+    enabled: Option<bool>,
+    concurrent_hashes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct MempoolSection {
+    max_transactions: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct StorageSection {
+    key_file: Option<PathBuf>,
+    peers_file: Option<PathBuf>,
+    chain_file: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ServerSection {
+    enabled: Option<bool>,
+    bind: Option<String>,
+}
+
+impl ServerSection {
+    /// `--<name>-bind <addr>` or `--<name>`, unless disabled.
+    fn into_args(self, name: &str, args: &mut Vec<String>) {
+        match (self.enabled, self.bind) {
+            (Some(false), _) => {}
+            (_, Some(bind)) => args.extend([format!("--{}-bind", name), bind]),
+            (Some(true), None) => args.push(format!("--{}", name)),
+            (None, None) => {}
+        }
+    }
+}
+
+impl ConfigFile {
+    fn parse(path: &Path, contents: &str) -> Result<Self, String> {
+        let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            _ => toml::from_str(contents).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+    }
+
+    /// The file's settings as command line options, so all layers go through one parser.
+    fn into_args(self, data_dir: &Path) -> Vec<String> {
+        let mut args = vec![];
+        let mut push = |flag: &str, value: String| {
+            args.push(flag.to_string());
+            args.push(value);
+        };
+        let path = |path: PathBuf| data_dir.join(path).display().to_string();
+        if let Some(network) = self.network {
+            push("--network", network);
+        }
+        for addr in self.listen {
+            push("--listen", addr);
+        }
+        for addr in self.bootstrap {
+            push("--bootstrap", addr);
+        }
+        if let Some(hashes) = self.mining.concurrent_hashes {
+            push("--concurrent-hashes", hashes.to_string());
+        }
+        if let Some(max) = self.mempool.max_transactions {
+            push("--max-pending-txs", max.to_string());
+        }
+        if let Some(key_file) = self.storage.key_file {
+            push("--key-file", path(key_file));
+        }
+        if let Some(peers_file) = self.storage.peers_file {
+            push("--peers-file", path(peers_file));
+        }
+        if let Some(chain_file) = self.storage.chain_file {
+            push("--chain-file", path(chain_file));
+        }
+        if let Some(pid_file) = self.storage.pid_file {
+            push("--pid-file", path(pid_file));
+        }
+        self.rpc.into_args("rpc", &mut args);
+        self.rest.into_args("rest", &mut args);
+        self.ws.into_args("ws", &mut args);
+        self.metrics.into_args("metrics", &mut args);
+        if self.mdns == Some(false) {
+            args.push(String::from("--no-mdns"));
+        }
+        if self.dandelion == Some(true) {
+            args.push(String::from("--dandelion"));
+        }
+        if self.mining.enabled == Some(true) {
+            args.push(String::from("--mine"));
+        }
+        args
+    }
+}
+
+/// `ELEMCHAIN_<OPTION>` variables as command line options. Lists are comma separated,
+/// `ELEMCHAIN_MDNS`, `ELEMCHAIN_DANDELION`, `ELEMCHAIN_MINE` and the server switches `ELEMCHAIN_RPC`,
+/// `ELEMCHAIN_REST`, `ELEMCHAIN_WS` and `ELEMCHAIN_METRICS` take `true` or `false`. Both turn into an
+/// option, so the environment overrides the config file either way.
+fn env_args<I: Iterator<Item = (String, String)>>(vars: I) -> Result<Vec<String>, String> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter_map(|(name, value)| Some((name.strip_prefix(ENV_PREFIX)?.to_lowercase().replace('_', "-"), value)))
+        .collect();
+    vars.sort();
+
+    let mut args = vec![];
+    for (option, value) in vars {
+        match option.as_str() {
+            "mdns" | "dandelion" | "mine" | "rpc" | "rest" | "ws" | "metrics" => {
+                let enabled: bool = value
+                    .parse()
+                    .map_err(|_| format!("{}{} expects true or false", ENV_PREFIX, option.to_uppercase()))?;
+                match enabled {
+                    true => args.push(format!("--{}", option)),
+                    false => args.push(format!("--no-{}", option)),
+                }
+            }
+            "listen" | "bootstrap" => {
+                for addr in value.split(',').map(str::trim).filter(|addr| !addr.is_empty()) {
+                    args.push(format!("--{}", option));
+                    args.push(addr.to_string());
+                }
+            }
+            "network" | "concurrent-hashes" | "max-pending-txs" | "key-file" | "peers-file" | "chain-file"
+            | "pid-file" | "rpc-bind" | "rest-bind" | "ws-bind"
+            | "metrics-bind" => {
+                args.push(format!("--{}", option));
+                args.push(value);
+            }
+            // Read before the config file, or not ours
+            _ => {}
+        }
+    }
+    Ok(args)
+}
+
+impl Config {
+    /// Defaults for a node keeping everything in `data_dir`.
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Self {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        let network = NetworkConfig {
+            key_file: Some(data_dir.join(DEFAULT_KEY_FILE)),
+            address_book: Some(data_dir.join(DEFAULT_ADDRESS_BOOK)),
+            ..NetworkConfig::default()
+        };
+        Config {
+            chain_file: Some(chain_file(&data_dir, &network.network_id)),
+            pid_file: Some(data_dir.join(DEFAULT_PID_FILE)),
+            network,
+            concurrent_hashes: DEFAULT_CONCURRENT_HASHES,
+            max_pending_txs: DEFAULT_MAX_PENDING_TXS,
+            mine: false,
+            rpc_addr: None,
+            rest_addr: None,
+            ws_addr: None,
+            metrics_addr: None,
+            data_dir,
+        }
+    }
+
+    /// Builds the config from the defaults, the config file, the environment and `args`,
+    /// each overriding the ones before. `--data-dir <path>` and `--config <path>` are read
+    /// from `args` first, or from `ELEMCHAIN_DATA_DIR` and `ELEMCHAIN_CONFIG`.
+    pub fn load<I, E>(args: I, vars: E) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+        E: Iterator<Item = (String, String)>,
+    {
+        let vars: Vec<(String, String)> = vars.collect();
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| var.strip_prefix(ENV_PREFIX) == Some(name))
+                .map(|(_, value)| PathBuf::from(value))
+        };
+        let mut data_dir = var("DATA_DIR").unwrap_or_else(default_data_dir);
+        let mut config_file = var("CONFIG");
+        let mut rest = vec![];
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--data-dir" => data_dir = args.next().map(PathBuf::from).ok_or("--data-dir expects a path")?,
+                "--config" => config_file = Some(args.next().map(PathBuf::from).ok_or("--config expects a path")?),
+                _ => rest.push(arg),
+            }
+        }
+
+        let mut config = Config::new(&data_dir);
+        // Only a config file asked for has to exist
+        let (path, required) = match config_file {
+            Some(path) => (path, true),
+            None => (data_dir.join(CONFIG_FILE), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => config.apply_args(ConfigFile::parse(&path, &contents)?.into_args(&data_dir))?,
+            Err(e) if required || path.exists() => {
+                return Err(format!("could not read config file {}: {}", path.display(), e))
+            }
+            Err(_) => {}
+        }
+        config.apply_args(env_args(vars.into_iter())?)?;
+        config.apply_args(rest)?;
+        Ok(config)
+    }
+
+    /// Overrides the settings given in `args`, handing network options on to `NetworkConfig`.
+    pub fn apply_args(&mut self, args: Vec<String>) -> Result<(), String> {
+        let network_id = self.network.network_id.clone();
+        let mut chain_file_set = false;
+        let mut network_args = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = |value: Option<String>| value.ok_or(format!("{} expects a value", arg));
+            match arg.as_str() {
+                "--concurrent-hashes" => {
+                    self.concurrent_hashes = parse_value(&arg, value(args.next())?)?;
+                    if self.concurrent_hashes == 0 {
+                        return Err(String::from("--concurrent-hashes has to be at least 1"));
+                    }
+                }
+                "--mine" => self.mine = true,
+                "--no-mine" => self.mine = false,
+                "--max-pending-txs" => self.max_pending_txs = parse_value(&arg, value(args.next())?)?,
+                "--chain-file" => {
+                    self.chain_file = Some(PathBuf::from(value(args.next())?));
+                    chain_file_set = true;
+                }
+                "--pid-file" => self.pid_file = Some(PathBuf::from(value(args.next())?)),
+                "--rpc" => {
+                    self.rpc_addr.get_or_insert(DEFAULT_RPC_ADDR.parse().expect("valid socket address"));
+                }
+                "--no-rpc" => self.rpc_addr = None,
+                "--rpc-bind" => self.rpc_addr = Some(parse_value(&arg, value(args.next())?)?),
+                "--rest" => {
+                    self.rest_addr.get_or_insert(DEFAULT_REST_ADDR.parse().expect("valid socket address"));
+                }
+                "--no-rest" => self.rest_addr = None,
+                "--rest-bind" => self.rest_addr = Some(parse_value(&arg, value(args.next())?)?),
+                "--ws" => {
+                    self.ws_addr.get_or_insert(DEFAULT_WS_ADDR.parse().expect("valid socket address"));
+                }
+                "--no-ws" => self.ws_addr = None,
+                "--ws-bind" => self.ws_addr = Some(parse_value(&arg, value(args.next())?)?),
+                "--metrics" => {
+                    self.metrics_addr.get_or_insert(DEFAULT_METRICS_ADDR.parse().expect("valid socket address"));
+                }
+                "--no-metrics" => self.metrics_addr = None,
+                "--metrics-bind" => self.metrics_addr = Some(parse_value(&arg, value(args.next())?)?),
+                "--ephemeral" => {
+                    self.chain_file = None;
+                    self.pid_file = None;
+                    network_args.push(arg);
+                }
+                _ => network_args.push(arg),
+            }
+        }
+        self.network.apply_args(network_args.into_iter())?;
+
+        // The default chain file follows the network, one given explicitly stays put
+        let default_chain = Some(chain_file(&self.data_dir, &network_id));
+        if !chain_file_set && self.chain_file == default_chain {
+            self.chain_file = Some(chain_file(&self.data_dir, &self.network.network_id));
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+fn chain_file(data_dir: &Path, network_id: &str) -> PathBuf {
+    data_dir.join(CHAIN_DIR).join(format!("{}.archive", network_id))
+}
+
+/// `~/.elemchain`, or `.elemchain` in the working directory without a home directory.
+pub fn default_data_dir() -> PathBuf {
+    match env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home).join(DEFAULT_DATA_DIR),
+        None => PathBuf::from(DEFAULT_DATA_DIR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, DEFAULT_CONCURRENT_HASHES};
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_layers() {
+        let data_dir = env::temp_dir().join(format!("elemchain-test-{}-config", process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(
+            data_dir.join("config.toml"),
+            r#"
+                network = "test"
+                listen = ["/ip4/0.0.0.0/tcp/4001", "/ip4/0.0.0.0/tcp/4002"]
+                mdns = false
+
+                [mining]
+                enabled = true
+                concurrent-hashes = 64
+
+                [storage]
+                key-file = "wallet.key"
+
+                [rpc]
+                bind = "127.0.0.1:9000"
+
+                [ws]
+                enabled = true
+            "#,
+        )
+        .unwrap();
+        let dir = data_dir.display().to_string();
+
+        let config = Config::load(args(&["--data-dir", &dir]), vars(&[])).unwrap();
+        assert_eq!(config.network.network_id, "test");
+        assert_eq!(config.network.listen_addrs.len(), 2);
+        assert!(!config.network.mdns);
+        assert_eq!(config.concurrent_hashes, 64);
+        assert!(config.mine);
+        assert_eq!(config.network.key_file, Some(data_dir.join("wallet.key")));
+        assert_eq!(config.network.address_book, Some(data_dir.join("peers.json")));
+        assert_eq!(config.chain_file, Some(data_dir.join("chain").join("test.archive")));
+        assert_eq!(config.rpc_addr, Some("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(config.ws_addr, Some("127.0.0.1:8546".parse().unwrap()));
+
+        // The environment beats the file, the command line beats both
+        let env = vars(&[
+            ("ELEMCHAIN_DATA_DIR", &dir),
+            ("ELEMCHAIN_NETWORK", "main"),
+            ("ELEMCHAIN_LISTEN", "/ip4/0.0.0.0/tcp/5001"),
+            ("ELEMCHAIN_MAX_PENDING_TXS", "10"),
+            ("ELEMCHAIN_REST", "true"),
+            ("ELEMCHAIN_METRICS_BIND", "0.0.0.0:9000"),
+            ("OTHER_NETWORK", "nope"),
+        ]);
+        let config = Config::load(args(&["--concurrent-hashes", "8", "--ephemeral"]), env).unwrap();
+        assert_eq!(config.network.network_id, "main");
+        assert_eq!(config.network.listen_addrs, vec!["/ip4/0.0.0.0/tcp/5001".parse().unwrap()]);
+        assert_eq!(config.max_pending_txs, 10);
+        assert_eq!(config.concurrent_hashes, 8);
+        assert_eq!(config.rest_addr, Some("127.0.0.1:8080".parse().unwrap()));
+        assert_eq!(config.metrics_addr, Some("0.0.0.0:9000".parse().unwrap()));
+        assert_eq!((config.network.key_file, config.chain_file, config.pid_file), (None, None, None));
+
+        fs::write(data_dir.join("bad.toml"), "netwrok = \"test\"").unwrap();
+        let bad = data_dir.join("bad.toml").display().to_string();
+        assert!(Config::load(args(&["--config", &bad]), vars(&[])).is_err());
+        assert!(Config::load(args(&["--config", "/nonexistent.toml"]), vars(&[])).is_err());
+        assert!(Config::load(args(&[]), vars(&[("ELEMCHAIN_MDNS", "maybe")])).is_err());
+
+        // Switches override the layer below them both ways
+        let env = vars(&[("ELEMCHAIN_DATA_DIR", &dir), ("ELEMCHAIN_MDNS", "true")]);
+        assert!(Config::load(args(&[]), env).unwrap().network.mdns);
+        let env = vars(&[("ELEMCHAIN_DANDELION", "true")]);
+        assert!(!Config::load(args(&["--no-dandelion"]), env).unwrap().network.dandelion);
+        let env = vars(&[("ELEMCHAIN_DATA_DIR", &dir), ("ELEMCHAIN_RPC", "false")]);
+        assert_eq!(Config::load(args(&[]), env).unwrap().rpc_addr, None);
+        let env = vars(&[("ELEMCHAIN_DATA_DIR", &dir), ("ELEMCHAIN_RPC", "true")]);
+        assert_eq!(Config::load(args(&[]), env).unwrap().rpc_addr, Some("127.0.0.1:9000".parse().unwrap()));
+        let env = vars(&[("ELEMCHAIN_REST", "true")]);
+        assert_eq!(Config::load(args(&["--no-rest"]), env).unwrap().rest_addr, None);
+        let env = vars(&[("ELEMCHAIN_DATA_DIR", &dir), ("ELEMCHAIN_WS", "false")]);
+        assert_eq!(Config::load(args(&[]), env).unwrap().ws_addr, None);
+        let env = vars(&[("ELEMCHAIN_METRICS", "false")]);
+        assert!(Config::load(args(&["--metrics"]), env).unwrap().metrics_addr.is_some());
+        let env = vars(&[("ELEMCHAIN_DATA_DIR", &dir), ("ELEMCHAIN_MINE", "false")]);
+        assert!(!Config::load(args(&[]), env).unwrap().mine);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::load(args(&["--data-dir", "node2"]), vars(&[])).unwrap();
+        assert_eq!(config.network.key_file, Some(PathBuf::from("node2/node.key")));
+        assert_eq!(config.pid_file, Some(PathBuf::from("node2/elemchain.pid")));
+        assert_eq!(config.chain_file, Some(PathBuf::from("node2/chain/main.archive")));
+        assert_eq!(config.concurrent_hashes, DEFAULT_CONCURRENT_HASHES);
+        assert!(!config.mine);
+        assert_eq!((config.rpc_addr, config.rest_addr, config.ws_addr), (None, None, None));
+        assert_eq!(config.metrics_addr, None);
+
+        let json = env::temp_dir().join(format!("elemchain-test-{}.json", process::id()));
+        fs::write(&json, r#"{ "network": "test", "mempool": { "max-transactions": 5 } }"#).unwrap();
+        let config = Config::load(args(&["--config", &json.display().to_string()]), vars(&[])).unwrap();
+        assert_eq!(config.network.network_id, "test");
+        assert_eq!(config.max_pending_txs, 5);
+        fs::remove_file(&json).unwrap();
+    }
+}
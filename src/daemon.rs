@@ -0,0 +1,175 @@
+use libp2p::Swarm;
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use crate::{
+    archive,
+    config::Config,
+    error::NodeError,
+    node::Node,
+    p2p::{self, AppBehaviour},
+    params::ChainParams,
+};
+
+pub const DEFAULT_PID_FILE: &str = "elemchain.pid";
+/// Time given to peers to see our connections close before exiting.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+// Keeps a second node from running on the same data directory. The pid file is
+// locked by the OS rather than just created, so a killed node does not leave a stale lock.
+#[derive(Debug)]
+pub struct PidFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self, NodeError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(NodeError::AlreadyRunning {
+                    path,
+                    pid: pid.trim().parse().ok(),
+                });
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(PidFile { file, path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Removed while still locked, the lock itself goes with the file handle
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Resolves on Ctrl-C, and on SIGTERM on unix.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// A node as configured, continuing the chain stored in the data directory. A chain file which
+/// does not load is moved aside to `<name>.bad`, so saving the new chain does not overwrite it.
+pub fn open_node(config: &Config) -> Result<Node, NodeError> {
+    let params = ChainParams::by_network_id(&config.network.network_id).expect("network id is checked by config");
+    let mut node = Node::from_params(params, config.concurrent_hashes);
+    node.max_pending_txs = config.max_pending_txs;
+    if let Some(path) = config.chain_file.as_ref().filter(|path| path.exists()) {
+        match archive::import_from_file(&mut node, path) {
+            Ok(summary) => info!("loaded {} blocks from {}", summary.imported, path.display()),
+            // Starting over is fine, peers hand us the chain again
+            Err(e) => {
+                let mut bad = path.clone().into_os_string();
+                bad.push(".bad");
+                let bad = PathBuf::from(bad);
+                if let Err(rename) = fs::rename(path, &bad) {
+                    warn!("could not move {} aside: {}", path.display(), rename);
+                    return Err(NodeError::Archive(e));
+                }
+                warn!("could not load chain from {}: {}, moved it to {}", path.display(), e, bad.display());
+            }
+        }
+    }
+    Ok(node)
+}
+
+/// Writes the chain to the configured chain file, if any.
+pub fn save_chain(swarm: &Swarm<AppBehaviour>, config: &Config) {
+    let path = match &config.chain_file {
+        Some(path) => path,
+        None => return,
+    };
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(archive::ArchiveError::from)
+        .and_then(|()| archive::save_chain(&swarm.behaviour().node.blockchain, path));
+    if let Err(e) = saved {
+        warn!("could not save chain to {}: {}", path.display(), e);
+    }
+}
+
+/// Saves the chain and the address book and hangs up on every peer, so they drop us
+/// right away instead of waiting for the connection to time out.
+pub async fn shutdown(swarm: &mut Swarm<AppBehaviour>, config: &Config) {
+    save_chain(swarm, config);
+    swarm.behaviour_mut().save_address_book();
+    for peer in swarm.behaviour().connected_peers() {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    p2p::run_for(swarm, SHUTDOWN_GRACE).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::daemon::{open_node, PidFile};
+    use crate::error::NodeError;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_pid_file() {
+        let path = env::temp_dir().join(format!("elemchain-test-{}.pid", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let lock = PidFile::acquire(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), process::id().to_string());
+        match PidFile::acquire(&path) {
+            Err(NodeError::AlreadyRunning { pid, .. }) => assert_eq!(pid, Some(process::id())),
+            other => panic!("second lock: {:?}", other),
+        }
+
+        drop(lock);
+        assert!(!path.exists());
+        drop(PidFile::acquire(&path).unwrap());
+    }
+
+    #[test]
+    fn test_unreadable_chain_is_kept() {
+        let path = env::temp_dir().join(format!("elemchain-test-{}.archive", process::id()));
+        let bad = path.with_extension("archive.bad");
+        fs::write(&path, "not an archive").unwrap();
+
+        let config = Config {
+            chain_file: Some(path.clone()),
+            ..Config::new("")
+        };
+        assert_eq!(open_node(&config).unwrap().blockchain.len(), 1);
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&bad).unwrap(), "not an archive");
+        fs::remove_file(&bad).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use libp2p::PeerId;
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::transaction::Transaction;
+
+/// How long stem relays and the fluff decision are kept.
+pub const EPOCH_DURATION: Duration = Duration::from_secs(10 * 60);
+/// Chance that a node fluffs every stem transaction it gets during an epoch.
+pub const FLUFF_PROBABILITY: f64 = 0.1;
+/// Peers stem transactions are forwarded to per epoch.
+pub const STEM_RELAYS: usize = 2;
+/// A stem transaction not seen broadcast by then is broadcast by us. The jitter keeps
+/// the nodes along a stem from all firing at once.
+pub const EMBARGO_BASE: Duration = Duration::from_secs(30);
+pub const EMBARGO_JITTER: Duration = Duration::from_secs(30);
+/// Stem transactions kept at once, further ones are broadcast right away.
+pub const MAX_EMBARGOED: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    /// Forward to this peer only.
+    Stem(PeerId),
+    /// Broadcast over gossip.
+    Fluff,
+}
+
+// Dandelion++ relay state. New transactions first travel along a stem of single peers,
+// so the first node to broadcast them is most likely not the one they came from.
+#[derive(Debug, Default)]
+pub struct Dandelion {
+    epoch_start: Option<Instant>,
+    fluffing: bool,
+    relays: Vec<PeerId>,
+    // Relay for the stem transactions of each peer, fixed for the epoch. `None` is our own.
+    routes: HashMap<Option<PeerId>, PeerId>,
+    embargoed: HashMap<String, (Transaction, Instant)>,
+}
+
+impl Dandelion {
+    fn new_epoch<R: Rng>(&mut self, peers: &[PeerId], now: Instant, rng: &mut R) {
+        self.epoch_start = Some(now);
+        self.fluffing = rng.gen_bool(FLUFF_PROBABILITY);
+        self.relays = peers.choose_multiple(rng, STEM_RELAYS).copied().collect();
+        self.routes.clear();
+    }
+
+    /// Where a stem transaction from `source` goes next, `None` for our own transactions.
+    pub fn route<R: Rng>(&mut self, source: Option<PeerId>, peers: &[PeerId], now: Instant, rng: &mut R) -> Route {
+        let expired = match self.epoch_start {
+            Some(start) => now.duration_since(start) >= EPOCH_DURATION,
+            None => true,
+        };
+        if expired || self.relays.iter().any(|relay| !peers.contains(relay)) {
+            self.new_epoch(peers, now, rng);
+        }
+        // Our own transactions always take the stem, otherwise a fluffing node would reveal them
+        if source.is_some() && self.fluffing {
+            return Route::Fluff;
+        }
+        // Sending a transaction straight back tells its sender we are not the source
+        let candidates: Vec<PeerId> = self
+            .relays
+            .iter()
+            .filter(|relay| Some(**relay) != source)
+            .copied()
+            .collect();
+        match self.routes.get(&source) {
+            Some(relay) => Route::Stem(*relay),
+            None => match candidates.choose(rng) {
+                Some(relay) => {
+                    self.routes.insert(source, *relay);
+                    Route::Stem(*relay)
+                }
+                None => Route::Fluff,
+            },
+        }
+    }
+
+    /// Keeps `tx` until it is seen broadcast. False if it is kept already or there is no room.
+    pub fn embargo<R: Rng>(&mut self, tx: Transaction, now: Instant, rng: &mut R) -> bool {
+        let id = tx.id();
+        if self.embargoed.contains_key(&id) || self.embargoed.len() >= MAX_EMBARGOED {
+            return false;
+        }
+        let jitter = EMBARGO_JITTER.mul_f64(rng.gen());
+        self.embargoed.insert(id, (tx, now + EMBARGO_BASE + jitter));
+        true
+    }
+
+    pub fn is_embargoed(&self, id: &str) -> bool {
+        self.embargoed.contains_key(id)
+    }
+
+    /// Drops the embargo of a transaction which got broadcast.
+    pub fn fluffed(&mut self, id: &str) {
+        self.embargoed.remove(id);
+    }
+
+    /// Transactions whose embargo ran out. The stem was probably broken, so they
+    /// have to be broadcast now.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<Transaction> {
+        let expired: Vec<String> = self
+            .embargoed
+            .iter()
+            .filter(|(_, (_, until))| *until <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|id| self.embargoed.remove(id))
+            .map(|(tx, _)| tx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::dandelion::{Dandelion, Route, EMBARGO_BASE, EMBARGO_JITTER, EPOCH_DURATION};
+    use libp2p::PeerId;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Instant;
+
+    #[test]
+    fn test_routes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut dandelion = Dandelion::default();
+        let now = Instant::now();
+        assert_eq!(dandelion.route(None, &[], now, &mut rng), Route::Fluff);
+
+        let peers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        dandelion.fluffing = false;
+        dandelion.epoch_start = Some(now);
+        dandelion.relays = peers[..2].to_vec();
+
+        // Same relay for everything from one source during the epoch, never back to the source
+        let own = dandelion.route(None, &peers, now, &mut rng);
+        assert!(matches!(own, Route::Stem(relay) if peers[..2].contains(&relay)));
+        assert_eq!(dandelion.route(None, &peers, now, &mut rng), own);
+        for _ in 0..10 {
+            assert_eq!(dandelion.route(Some(peers[0]), &peers, now, &mut rng), Route::Stem(peers[1]));
+        }
+
+        // A fluffing node broadcasts what others send, but still stems its own
+        dandelion.fluffing = true;
+        assert_eq!(dandelion.route(Some(peers[3]), &peers, now, &mut rng), Route::Fluff);
+        assert_eq!(dandelion.route(None, &peers, now, &mut rng), own);
+
+        // Relays are picked again once one goes away or the epoch ends
+        dandelion.route(None, &peers[2..], now, &mut rng);
+        assert!(dandelion.relays.iter().all(|relay| peers[2..].contains(relay)));
+        let later = now + EPOCH_DURATION;
+        dandelion.route(None, &peers[2..], later, &mut rng);
+        assert_eq!(dandelion.epoch_start, Some(later));
+    }
+
+    #[test]
+    fn test_embargo() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut dandelion = Dandelion::default();
+        let now = Instant::now();
+        let tx = generate_blocks()[0].transactions[0].clone();
+        let mut other = tx.clone();
+        other.amount += 1;
+
+        assert!(dandelion.embargo(tx.clone(), now, &mut rng));
+        assert!(!dandelion.embargo(tx.clone(), now, &mut rng));
+        assert!(dandelion.embargo(other.clone(), now, &mut rng));
+        assert!(dandelion.is_embargoed(&tx.id()));
+
+        assert!(dandelion.take_expired(now + EMBARGO_BASE / 2).is_empty());
+        dandelion.fluffed(&other.id());
+        assert_eq!(dandelion.take_expired(now + EMBARGO_BASE + EMBARGO_JITTER), vec![tx.clone()]);
+        assert!(!dandelion.is_embargoed(&tx.id()));
+    }
+}
@@ -0,0 +1,273 @@
+use libp2p::{
+    identify::{Identify, IdentifyConfig},
+    identity::PublicKey,
+    kad::{store::MemoryStore, Kademlia, KademliaConfig},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{
+    address_book::DEFAULT_ADDRESS_BOOK,
+    keyfile::DEFAULT_KEY_FILE,
+    params::{ChainParams, MAIN_NETWORK},
+};
+
+pub const KADEMLIA_PROTOCOL: &[u8] = b"/elemchain/kad/1.0.0";
+pub const IDENTIFY_PROTOCOL: &str = "/elemchain/1.0.0";
+
+// How the node finds other nodes. mDNS only works inside one LAN, bootstrap peers
+// and Kademlia let nodes on different hosts form a network over plain TCP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    /// Which network's chain params to use, see `ChainParams::by_network_id`.
+    pub network_id: String,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub bootstrap_peers: Vec<Multiaddr>,
+    pub mdns: bool,
+    /// Where the node key is kept, `None` for a new identity on every start.
+    pub key_file: Option<PathBuf>,
+    /// Where known peers are kept, `None` to only remember them in memory.
+    pub address_book: Option<PathBuf>,
+    /// Relay new transactions along a Dandelion stem before broadcasting them.
+    pub dandelion: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            network_id: String::from(MAIN_NETWORK),
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")],
+            bootstrap_peers: vec![],
+            mdns: true,
+            key_file: Some(PathBuf::from(DEFAULT_KEY_FILE)),
+            address_book: Some(PathBuf::from(DEFAULT_ADDRESS_BOOK)),
+            dandelion: false,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Reads `--network <id>`, `--listen <addr>` (repeatable), `--bootstrap <addr>` (repeatable), `--[no-]mdns`,
+    /// `--key-file <path>`, `--peers-file <path>`, `--ephemeral` (persist neither) and `--[no-]dandelion`.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut config = NetworkConfig::default();
+        config.apply_args(args)?;
+        Ok(config)
+    }
+
+    /// Overrides the settings given in `args`. Listen addresses given here replace the
+    /// current ones, bootstrap peers are added to them.
+    pub fn apply_args<I: Iterator<Item = String>>(&mut self, mut args: I) -> Result<(), String> {
+        let mut listen_addrs = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--network" => {
+                    let id = args.next().ok_or("--network expects a network id")?;
+                    if ChainParams::by_network_id(&id).is_none() {
+                        return Err(format!("unknown network {}", id));
+                    }
+                    self.network_id = id;
+                }
+                "--listen" => listen_addrs.push(parse_addr_arg(&arg, args.next())?),
+                "--bootstrap" => self.bootstrap_peers.push(parse_addr_arg(&arg, args.next())?),
+                "--mdns" => self.mdns = true,
+                "--no-mdns" => self.mdns = false,
+                "--key-file" => self.key_file = Some(parse_path_arg(&arg, args.next())?),
+                "--peers-file" => self.address_book = Some(parse_path_arg(&arg, args.next())?),
+                "--ephemeral" => {
+                    self.key_file = None;
+                    self.address_book = None;
+                }
+                "--dandelion" => self.dandelion = true,
+                "--no-dandelion" => self.dandelion = false,
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        if !listen_addrs.is_empty() {
+            self.listen_addrs = listen_addrs;
+        }
+        Ok(())
+    }
+}
+
+fn parse_addr_arg(flag: &str, value: Option<String>) -> Result<Multiaddr, String> {
+    let value = value.ok_or(format!("{} expects a multiaddr", flag))?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid multiaddr {}: {}", value, e))
+}
+
+fn parse_path_arg(flag: &str, value: Option<String>) -> Result<PathBuf, String> {
+    value.map(PathBuf::from).ok_or(format!("{} expects a path", flag))
+}
+
+/// Splits `/ip4/1.2.3.4/tcp/4001/p2p/<peer id>` into the peer id and the address to dial.
+pub fn split_peer_id(addr: &Multiaddr) -> (Option<PeerId>, Multiaddr) {
+    let mut addr = addr.clone();
+    match addr.pop() {
+        Some(Protocol::P2p(hash)) => (PeerId::from_multihash(hash).ok(), addr),
+        Some(other) => {
+            addr.push(other);
+            (None, addr)
+        }
+        None => (None, addr),
+    }
+}
+
+pub fn new_kademlia(peer_id: PeerId) -> Kademlia<MemoryStore> {
+    let mut config = KademliaConfig::default();
+    config
+        .set_protocol_name(KADEMLIA_PROTOCOL)
+        .set_query_timeout(Duration::from_secs(30));
+    Kademlia::with_config(peer_id, MemoryStore::new(peer_id), config)
+}
+
+// Identify tells us the addresses a peer listens on, which Kademlia can share with others
+pub fn new_identify(public_key: PublicKey) -> Identify {
+    Identify::new(IdentifyConfig::new(IDENTIFY_PROTOCOL.to_string(), public_key))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::discovery::{split_peer_id, NetworkConfig};
+    use crate::node::Node;
+    use crate::params::ChainParams;
+    use crate::p2p::{self, AppBehaviour};
+    use libp2p::{futures::StreamExt, identity, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
+    use std::time::Duration;
+    use tokio::{select, time::sleep};
+
+    pub fn local_config() -> NetworkConfig {
+        NetworkConfig {
+            network_id: String::from("test"),
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            bootstrap_peers: vec![],
+            mdns: false,
+            key_file: None,
+            address_book: None,
+            dandelion: false,
+        }
+    }
+
+    /// A swarm listening on localhost, and its address including the peer id.
+    pub async fn local_swarm(params: ChainParams) -> (Swarm<AppBehaviour>, Multiaddr) {
+        let keys = identity::Keypair::generate_ed25519();
+        let node = Node::from_params(params, 256);
+        let mut swarm = p2p::swarm_factory(keys, node, &local_config()).await.build();
+        Swarm::listen_on(&mut swarm, local_config().listen_addrs[0].clone()).unwrap();
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                let peer_id = *swarm.local_peer_id();
+                return (swarm, address.with(libp2p::multiaddr::Protocol::P2p(peer_id.into())));
+            }
+        }
+    }
+
+    fn knows(swarm: &mut Swarm<AppBehaviour>, peer: &PeerId) -> bool {
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .any(|bucket| bucket.iter().any(|entry| entry.node.key.preimage() == peer))
+    }
+
+    #[test]
+    fn test_args() {
+        let args = vec![
+            "--listen",
+            "/ip4/127.0.0.1/tcp/4001",
+            "--bootstrap",
+            "/ip4/127.0.0.1/tcp/4002",
+            "--no-mdns",
+            "--network",
+            "test",
+            "--key-file",
+            "a.key",
+            "--dandelion",
+        ];
+        let config = NetworkConfig::from_args(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(config.listen_addrs, vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()]);
+        assert_eq!(config.bootstrap_peers.len(), 1);
+        assert!(!config.mdns);
+        assert_eq!(config.network_id, "test");
+        assert_eq!(config.key_file, Some("a.key".into()));
+        assert_eq!(config.address_book, NetworkConfig::default().address_book);
+        assert!(config.dandelion);
+
+        let mut config = NetworkConfig::from_args(vec![String::from("--ephemeral")].into_iter()).unwrap();
+        assert_eq!((config.key_file.clone(), config.address_book.clone()), (None, None));
+
+        // Later listen addresses replace earlier ones instead of adding to them
+        let listen = vec!["--listen", "/ip4/127.0.0.1/tcp/1", "--listen", "/ip4/127.0.0.1/tcp/2"];
+        config.apply_args(listen.into_iter().map(String::from)).unwrap();
+        assert_eq!(config.listen_addrs.len(), 2);
+        config
+            .apply_args(vec!["--listen", "/ip4/127.0.0.1/tcp/3"].into_iter().map(String::from))
+            .unwrap();
+        assert_eq!(config.listen_addrs, vec!["/ip4/127.0.0.1/tcp/3".parse().unwrap()]);
+
+        config
+            .apply_args(vec!["--mdns", "--dandelion", "--no-dandelion"].into_iter().map(String::from))
+            .unwrap();
+        assert!(config.mdns && !config.dandelion);
+
+        assert!(NetworkConfig::from_args(vec![String::from("--bootstrap")].into_iter()).is_err());
+        assert!(NetworkConfig::from_args(vec![String::from("--nope")].into_iter()).is_err());
+        let unknown_network = vec!["--network", "nope"].into_iter().map(String::from);
+        assert!(NetworkConfig::from_args(unknown_network).is_err());
+    }
+
+    #[test]
+    fn test_split_peer_id() {
+        let peer = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer).parse().unwrap();
+        let (id, dial) = split_peer_id(&addr);
+        assert_eq!(id, Some(peer));
+        assert_eq!(dial, "/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+
+        let (id, dial) = split_peer_id(&dial);
+        assert_eq!(id, None);
+        assert_eq!(dial, "/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+    }
+
+    // Behaviour events are handled inside the swarm without surfacing as swarm events,
+    // hence the short sleeps in the loops below to re-check the condition
+    #[tokio::test]
+    async fn test_peers_find_each_other_through_bootstrap_node() {
+        let (mut bootstrap, bootstrap_addr) = local_swarm(ChainParams::test()).await;
+        let (mut first, _) = local_swarm(ChainParams::test()).await;
+        let (mut second, _) = local_swarm(ChainParams::test()).await;
+        let first_id = *first.local_peer_id();
+        let second_id = *second.local_peer_id();
+
+        // First peer joins, the bootstrap node learns its listen address
+        p2p::dial(&mut first, &bootstrap_addr);
+        let deadline = sleep(Duration::from_secs(20));
+        tokio::pin!(deadline);
+        while !knows(&mut bootstrap, &first_id) {
+            select! {
+                e = bootstrap.select_next_some() => { p2p::handle_swarm_event(&mut bootstrap, e); },
+                e = first.select_next_some() => { p2p::handle_swarm_event(&mut first, e); },
+                _ = sleep(Duration::from_millis(100)) => {},
+                _ = &mut deadline => panic!("bootstrap node never learnt about the first peer"),
+            }
+        }
+
+        // Second peer only knows the bootstrap node and discovers the first one through Kademlia
+        p2p::dial(&mut second, &bootstrap_addr);
+        second.behaviour_mut().bootstrap_discovery();
+        while !second.behaviour().connected_peers().contains(&first_id)
+            || !first.behaviour().connected_peers().contains(&second_id)
+        {
+            select! {
+                e = bootstrap.select_next_some() => { p2p::handle_swarm_event(&mut bootstrap, e); },
+                e = first.select_next_some() => { p2p::handle_swarm_event(&mut first, e); },
+                e = second.select_next_some() => { p2p::handle_swarm_event(&mut second, e); },
+                _ = sleep(Duration::from_millis(100)) => {},
+                _ = &mut deadline => panic!("second peer never connected to the first"),
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::archive::ArchiveError;
+#[cfg(feature = "network")]
+use crate::{handshake::HandshakeError, keyfile::KeyFileError, message::MessageError};
+
+/// Why a transaction is not valid on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    NonPositiveAmount(i32),
+    /// Sender or receiver is empty.
+    MissingParty,
+    SelfTransfer,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::NonPositiveAmount(amount) => write!(f, "amount {} is not positive", amount),
+            TransactionError::MissingParty => write!(f, "sender or receiver is missing"),
+            TransactionError::SelfTransfer => write!(f, "sender and receiver are the same"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// Why a block or chain is not valid.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The hash does not match the block's contents.
+    InvalidHash,
+    InsufficientWork { difficulty: usize },
+    /// The block's parent is not the block it should follow.
+    UnknownParent(String),
+    InvalidTransaction { index: usize, error: TransactionError },
+    TooFewTransactions { found: usize, min: usize },
+    /// Encoded size of the block in bytes.
+    TooLarge { size: usize, max: usize },
+    EmptyChain,
+    DifferentGenesis,
+    /// The block at `height` does not link to the one before it.
+    BrokenLink { height: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::InvalidHash => write!(f, "block hash does not match its contents"),
+            ValidationError::InsufficientWork { difficulty } => {
+                write!(f, "block hash has less than {} leading zeros", difficulty)
+            }
+            ValidationError::UnknownParent(hash) => write!(f, "unknown parent block {}", hash),
+            ValidationError::InvalidTransaction { index, error } => write!(f, "transaction {}: {}", index, error),
+            ValidationError::TooFewTransactions { found, min } => {
+                write!(f, "{} transactions, a block needs at least {}", found, min)
+            }
+            ValidationError::TooLarge { size, max } => {
+                write!(f, "block of {} bytes exceeds the limit of {}", size, max)
+            }
+            ValidationError::EmptyChain => write!(f, "chain is empty"),
+            ValidationError::DifferentGenesis => write!(f, "chain starts from another genesis block"),
+            ValidationError::BrokenLink { height } => write!(f, "block {} does not link to its parent", height),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ValidationError::InvalidTransaction { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Everything that can go wrong in the node, for callers which just report errors.
+#[derive(Debug)]
+pub enum NodeError {
+    Config(String),
+    /// A command could not do what it was asked for.
+    Command(String),
+    Validation(ValidationError),
+    #[cfg(feature = "network")]
+    Message(MessageError),
+    #[cfg(feature = "network")]
+    Handshake(HandshakeError),
+    Archive(ArchiveError),
+    #[cfg(feature = "network")]
+    KeyFile { path: PathBuf, error: KeyFileError },
+    #[cfg(feature = "network")]
+    Transport(String),
+    /// Another node holds the pid file.
+    AlreadyRunning { path: PathBuf, pid: Option<u32> },
+    Io(io::Error),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeError::Config(e) => write!(f, "invalid configuration: {}", e),
+            NodeError::Command(e) => write!(f, "{}", e),
+            NodeError::Validation(e) => write!(f, "validation failed: {}", e),
+            #[cfg(feature = "network")]
+            NodeError::Message(e) => write!(f, "{}", e),
+            #[cfg(feature = "network")]
+            NodeError::Handshake(e) => write!(f, "handshake failed: {}", e),
+            NodeError::Archive(e) => write!(f, "archive error: {}", e),
+            #[cfg(feature = "network")]
+            NodeError::KeyFile { path, error } => write!(f, "could not load node key {}: {}", path.display(), error),
+            #[cfg(feature = "network")]
+            NodeError::Transport(e) => write!(f, "transport error: {}", e),
+            NodeError::AlreadyRunning { path, pid: Some(pid) } => {
+                write!(f, "another node (pid {}) is running, see {}", pid, path.display())
+            }
+            NodeError::AlreadyRunning { path, pid: None } => {
+                write!(f, "another node is running, see {}", path.display())
+            }
+            NodeError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NodeError::Validation(e) => Some(e),
+            #[cfg(feature = "network")]
+            NodeError::Message(e) => Some(e),
+            NodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ValidationError> for NodeError {
+    fn from(e: ValidationError) -> Self {
+        NodeError::Validation(e)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<MessageError> for NodeError {
+    fn from(e: MessageError) -> Self {
+        NodeError::Message(e)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<HandshakeError> for NodeError {
+    fn from(e: HandshakeError) -> Self {
+        NodeError::Handshake(e)
+    }
+}
+
+impl From<ArchiveError> for NodeError {
+    fn from(e: ArchiveError) -> Self {
+        NodeError::Archive(e)
+    }
+}
+
+impl From<io::Error> for NodeError {
+    fn from(e: io::Error) -> Self {
+        NodeError::Io(e)
+    }
+}
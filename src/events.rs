@@ -0,0 +1,46 @@
+use crate::{block::Block, transaction::Transaction};
+
+/// Something that changed in the node, published as it happens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A block joined our best chain, mined, received or synced.
+    BlockConnected { height: usize, block: Block },
+    /// A block left our best chain, for a chain with more work or because it was broken.
+    /// Published tip first.
+    BlockDisconnected { height: usize, block: Block },
+    /// A transaction entered the pending pool.
+    TxAccepted(Transaction),
+    /// A transaction left the pending pool.
+    TxEvicted { tx: Transaction, reason: EvictionReason },
+    /// A peer completed the handshake.
+    PeerConnected { peer: String, best_height: usize },
+    /// Block bodies downloaded out of those the running sync fetches.
+    SyncProgress { downloaded: usize, total: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionReason {
+    /// Made it into a block.
+    Confirmed,
+}
+
+pub type Subscriber = Box<dyn FnMut(&Event) + Send>;
+
+// Subscribers run synchronously, in the order they subscribed, on whatever is changing
+// the node. Anything slow belongs on the other end of a channel.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventBus {
+    pub fn subscribe<F: FnMut(&Event) + Send + 'static>(&mut self, subscriber: F) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    pub fn publish(&mut self, event: Event) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use libp2p::gossipsub::{
+    GossipsubConfig, GossipsubConfigBuilder, GossipsubMessage, IdentTopic, MessageAcceptance, MessageId,
+    PeerScoreParams, PeerScoreThresholds, TopicScoreParams, ValidationMode,
+};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use crate::{
+    block::Block,
+    message::{NetworkMessage, MAX_CHUNK_SIZE},
+    node::Node,
+    sync::TipAnnouncement,
+    transaction::Transaction,
+};
+
+pub const BLOCKS_TOPIC: &str = "blockchain";
+pub const TRANSACTIONS_TOPIC: &str = "transactions";
+
+pub fn blocks_topic() -> IdentTopic {
+    IdentTopic::new(BLOCKS_TOPIC)
+}
+
+pub fn transactions_topic() -> IdentTopic {
+    IdentTopic::new(TRANSACTIONS_TOPIC)
+}
+
+// Ids come from the block or transaction hash, so the same block announced by two
+// miners' neighbours is only forwarded once
+fn message_id(message: &GossipsubMessage) -> MessageId {
+    match NetworkMessage::decode(&message.data) {
+        Ok(NetworkMessage::TipAnnouncement(tip)) => MessageId::from(tip.header.hash),
+        Ok(NetworkMessage::Block { block, .. }) => MessageId::from(block.hash),
+        Ok(NetworkMessage::CompactBlock(compact)) => MessageId::from(compact.header.hash),
+        Ok(NetworkMessage::Transaction(tx)) => MessageId::from(tx.id()),
+        _ => MessageId::from(format!("{:x}", Sha256::digest(&message.data))),
+    }
+}
+
+pub fn gossipsub_config() -> GossipsubConfig {
+    GossipsubConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(1))
+        .validation_mode(ValidationMode::Strict)
+        .validate_messages()
+        .message_id_fn(message_id)
+        // Room for the largest block plus the gossipsub envelope around it
+        .max_transmit_size(MAX_CHUNK_SIZE + 16 * 1024)
+        .build()
+        .expect("valid gossipsub config")
+}
+
+/// Scoring only punishes invalid messages. Delivery rate penalties would hurt
+/// honest peers on a network which mines a block once in a while.
+pub fn topic_score_params() -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: 1.0,
+        first_message_deliveries_weight: 0.5,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: -10.0,
+        ..Default::default()
+    }
+}
+
+pub fn peer_score_params() -> (PeerScoreParams, PeerScoreThresholds) {
+    let mut params = PeerScoreParams::default();
+    for topic in [blocks_topic(), transactions_topic()] {
+        params.topics.insert(topic.hash(), topic_score_params());
+    }
+    (params, PeerScoreThresholds::default())
+}
+
+pub fn validate_tip(node: &Node, tip: &TipAnnouncement) -> MessageAcceptance {
+    // Nothing new, this also covers a genesis block which is not mined
+    if node.blockchain.height_of(&tip.header.hash).is_some() {
+        return MessageAcceptance::Ignore;
+    }
+    if !tip.header.has_valid_hash() || !tip.header.meets_difficulty(node.blockchain.difficulty()) {
+        return MessageAcceptance::Reject;
+    }
+    MessageAcceptance::Accept
+}
+
+/// A pushed block has to be as valid as any block we would fetch ourselves.
+pub fn validate_block(node: &Node, block: &Block) -> MessageAcceptance {
+    if node.blockchain.height_of(&block.hash).is_some() {
+        return MessageAcceptance::Ignore;
+    }
+    match block.validate(node.blockchain.difficulty()) {
+        Ok(()) => MessageAcceptance::Accept,
+        Err(_) => MessageAcceptance::Reject,
+    }
+}
+
+pub fn validate_transaction(node: &Node, tx: &Transaction) -> MessageAcceptance {
+    if !tx.is_valid() {
+        return MessageAcceptance::Reject;
+    }
+    if node.knows_transaction(&tx.id()) {
+        return MessageAcceptance::Ignore;
+    }
+    MessageAcceptance::Accept
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::gossip::{validate_block, validate_tip, validate_transaction};
+    use crate::{blockchain::Blockchain, node::Node, sync::TipAnnouncement};
+    use libp2p::gossipsub::MessageAcceptance;
+
+    #[test]
+    fn test_validation() {
+        let mut chain = Blockchain::new(1, 1, 256);
+        let tx = generate_blocks()[0].transactions[0].clone();
+        chain.try_mine(vec![tx.clone()]).unwrap();
+        let mut node = Node::new(Blockchain::new(1, 1, 256));
+
+        let tip = TipAnnouncement::from_chain(&chain).unwrap();
+        assert!(matches!(validate_tip(&node, &tip), MessageAcceptance::Accept));
+
+        let mut forged = tip.clone();
+        forged.header.nonce += 1;
+        assert!(matches!(validate_tip(&node, &forged), MessageAcceptance::Reject));
+
+        let mined = chain.chain.last().unwrap().clone();
+        assert!(matches!(validate_block(&node, &mined), MessageAcceptance::Accept));
+        let mut tampered = mined.clone();
+        tampered.transactions.clear();
+        assert!(matches!(validate_block(&node, &tampered), MessageAcceptance::Reject));
+
+        node.blockchain = chain;
+        assert!(matches!(validate_tip(&node, &tip), MessageAcceptance::Ignore));
+        assert!(matches!(validate_block(&node, &mined), MessageAcceptance::Ignore));
+
+        // Already confirmed in the mined block
+        assert!(matches!(validate_transaction(&node, &tx), MessageAcceptance::Ignore));
+
+        let mut new_tx = tx.clone();
+        new_tx.amount += 1;
+        assert!(matches!(validate_transaction(&node, &new_tx), MessageAcceptance::Accept));
+        node.add_pending_tx(new_tx.clone());
+        assert!(matches!(validate_transaction(&node, &new_tx), MessageAcceptance::Ignore));
+
+        let mut to_self = tx;
+        to_self.to = to_self.from.clone();
+        assert!(matches!(validate_transaction(&node, &to_self), MessageAcceptance::Reject));
+    }
+}
@@ -0,0 +1,223 @@
+use async_trait::async_trait;
+use libp2p::{
+    core::upgrade::ProtocolName,
+    futures::{io, AsyncRead, AsyncWrite},
+    request_response::{ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter;
+use std::time::Duration;
+
+use crate::{
+    message::{MessageError, NetworkMessage, MAX_HANDSHAKE_SIZE},
+    node::Node,
+    protocol::{invalid_data, read_message, write_message},
+};
+
+pub const HANDSHAKE_PROTOCOL: &[u8] = b"/elemchain/handshake/1.0.0";
+/// Version of the messages we speak. Bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest peer version we still understand.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const USER_AGENT: &str = concat!("elemchain/", env!("CARGO_PKG_VERSION"));
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Both sides send their handshake as a request right after connecting and answer
+// the other's with their own, so each learns about the other either way.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub network_id: String,
+    pub genesis_hash: String,
+    pub best_height: usize,
+    pub user_agent: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    IncompatibleVersion(u32),
+    WrongNetwork(String),
+    WrongGenesis(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::IncompatibleVersion(v) => write!(
+                f,
+                "protocol version {} is not in supported range {}..={}",
+                v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::WrongNetwork(id) => write!(f, "peer is on network {:?}", id),
+            HandshakeError::WrongGenesis(hash) => write!(f, "peer has genesis block {}", hash),
+        }
+    }
+}
+
+impl Handshake {
+    pub fn from_node(node: &Node) -> Self {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            network_id: node.params.network_id.clone(),
+            genesis_hash: node
+                .blockchain
+                .chain
+                .first()
+                .map(|block| block.hash.clone())
+                .unwrap_or_default(),
+            best_height: node.blockchain.len().saturating_sub(1),
+            user_agent: USER_AGENT.to_string(),
+        }
+    }
+
+    /// Checks whether a peer which sent `self` can join our network, described by `local`.
+    pub fn check_compatible(&self, local: &Handshake) -> Result<(), HandshakeError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version) {
+            return Err(HandshakeError::IncompatibleVersion(self.protocol_version));
+        }
+        if self.network_id != local.network_id {
+            return Err(HandshakeError::WrongNetwork(self.network_id.clone()));
+        }
+        if self.genesis_hash != local.genesis_hash {
+            return Err(HandshakeError::WrongGenesis(self.genesis_hash.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeProtocol;
+
+impl ProtocolName for HandshakeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        HANDSHAKE_PROTOCOL
+    }
+}
+
+#[derive(Clone)]
+pub struct HandshakeCodec;
+
+async fn read_handshake<T>(io: &mut T) -> io::Result<Handshake>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    match read_message(io, MAX_HANDSHAKE_SIZE).await? {
+        NetworkMessage::Handshake(handshake) => Ok(handshake),
+        other => Err(invalid_data(MessageError::UnexpectedKind(other.kind()))),
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for HandshakeCodec {
+    type Protocol = HandshakeProtocol;
+    type Request = Handshake;
+    type Response = Handshake;
+
+    async fn read_request<T>(&mut self, _: &HandshakeProtocol, io: &mut T) -> io::Result<Handshake>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_handshake(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &HandshakeProtocol, io: &mut T) -> io::Result<Handshake>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_handshake(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &HandshakeProtocol, io: &mut T, req: Handshake) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &NetworkMessage::Handshake(req)).await
+    }
+
+    async fn write_response<T>(&mut self, _: &HandshakeProtocol, io: &mut T, res: Handshake) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &NetworkMessage::Handshake(res)).await
+    }
+}
+
+pub fn new_handshake() -> RequestResponse<HandshakeCodec> {
+    let mut cfg = RequestResponseConfig::default();
+    cfg.set_request_timeout(HANDSHAKE_TIMEOUT);
+
+    RequestResponse::new(
+        HandshakeCodec,
+        iter::once((HandshakeProtocol, ProtocolSupport::Full)),
+        cfg,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::tests::local_swarm;
+    use crate::handshake::{Handshake, HandshakeError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use crate::node::Node;
+    use crate::p2p;
+    use crate::params::ChainParams;
+    use libp2p::futures::StreamExt;
+    use std::time::Duration;
+    use tokio::{select, time::sleep};
+
+    #[test]
+    fn test_compatibility() {
+        let local = Handshake::from_node(&Node::from_params(ChainParams::main(), 256));
+        assert_eq!(local.best_height, 0);
+
+        let mut remote = local.clone();
+        remote.best_height = 10;
+        remote.user_agent = String::from("other/0.1");
+        assert_eq!(remote.check_compatible(&local), Ok(()));
+
+        let mut old = remote.clone();
+        old.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        assert_eq!(
+            old.check_compatible(&local),
+            Err(HandshakeError::IncompatibleVersion(MIN_PROTOCOL_VERSION - 1))
+        );
+        let mut new = remote.clone();
+        new.protocol_version = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            new.check_compatible(&local),
+            Err(HandshakeError::IncompatibleVersion(PROTOCOL_VERSION + 1))
+        );
+
+        let test_net = Handshake::from_node(&Node::from_params(ChainParams::test(), 256));
+        assert!(matches!(test_net.check_compatible(&local), Err(HandshakeError::WrongNetwork(_))));
+
+        let mut fork = remote;
+        fork.genesis_hash = String::from("123");
+        assert!(matches!(fork.check_compatible(&local), Err(HandshakeError::WrongGenesis(_))));
+    }
+
+    #[tokio::test]
+    async fn test_peer_of_other_network_is_dropped() {
+        let (mut main, main_addr) = local_swarm(ChainParams::main()).await;
+        let (mut test, _) = local_swarm(ChainParams::test()).await;
+        let main_id = *main.local_peer_id();
+        let test_id = *test.local_peer_id();
+
+        p2p::dial(&mut test, &main_addr);
+        let deadline = sleep(Duration::from_secs(20));
+        tokio::pin!(deadline);
+        while !main.behaviour().is_incompatible(&test_id)
+            || !test.behaviour().is_incompatible(&main_id)
+            || main.is_connected(&test_id)
+        {
+            select! {
+                e = main.select_next_some() => { p2p::handle_swarm_event(&mut main, e); },
+                e = test.select_next_some() => { p2p::handle_swarm_event(&mut test, e); },
+                _ = sleep(Duration::from_millis(100)) => {},
+                _ = &mut deadline => panic!("peers of different networks were not disconnected"),
+            }
+        }
+        assert!(main.behaviour().connected_peers().is_empty());
+        assert!(test.behaviour().connected_peers().is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+use log::debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// Just enough HTTP/1.1 for the local control servers: one request at a time per
+// connection, bodies sized by Content-Length, no chunked encoding.
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string.
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of `name` in the query string, without any percent decoding.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        !matches!(self.header("connection"), Some(value) if value.eq_ignore_ascii_case("close"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Response {
+            status,
+            headers: vec![(String::from("Content-Type"), String::from("application/json"))],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Reads the next request, `None` once the client closed the connection.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut head_size = 0;
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        // Bounded, so a client can't make us buffer an endless line
+        let read = (&mut *reader)
+            .take((MAX_HEAD_SIZE - head_size + 1) as u64)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            return match lines.is_empty() {
+                true => Ok(None),
+                false => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        head_size += read;
+        if head_size > MAX_HEAD_SIZE {
+            return Err(invalid("request head too large"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            if lines.is_empty() {
+                // Stray newline between requests
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let request_line = lines.remove(0);
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Err(invalid("malformed request line")),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    let mut headers = vec![];
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        path,
+        query,
+        headers,
+        body: vec![],
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(invalid("chunked bodies are not supported"));
+    }
+    let length: usize = match request.header("content-length") {
+        Some(length) => length.parse().map_err(|_| invalid("invalid content length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(invalid("request body too large"));
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).await?;
+    Ok(Some(request))
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &Response) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // Upgraded connections carry no body of their own
+    if response.status != 101 {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await
+}
+
+/// Accepts connections for good, answering each request with `handler`.
+pub async fn serve<H, F>(listener: TcpListener, handler: H)
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(handle_connection(stream, addr, handler.clone()));
+            }
+            Err(e) => debug!("accept failed: {}", e),
+        }
+    }
+}
+
+async fn handle_connection<H, F>(stream: TcpStream, addr: SocketAddr, handler: H)
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let request = match read_request(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("bad request from {}: {}", addr, e);
+                let _ = write_response(&mut writer, &Response::empty(400)).await;
+                return;
+            }
+        };
+        let keep_alive = request.keep_alive();
+        let response = handler(request).await;
+        if write_response(&mut writer, &response).await.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{read_request, write_response, Response, MAX_BODY_SIZE};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = "POST /rpc?verbose=1&x=2 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody\
+                   GET /status HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = raw.as_bytes();
+
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/rpc"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.query_param("x"), Some("2"));
+        assert_eq!(request.body, b"body");
+        assert!(request.keep_alive());
+
+        let request = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(request.path, "/status");
+        assert!(!request.keep_alive());
+        assert_eq!(read_request(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let oversized = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert!(read_request(&mut oversized.as_bytes()).await.is_err());
+        let endless = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000));
+        assert!(read_request(&mut endless.as_bytes()).await.is_err());
+        assert!(read_request(&mut "hello\r\n\r\n".as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let mut out = vec![];
+        write_response(&mut out, &Response::json(200, &json!({ "ok": true }))).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\r\n{\"ok\":true}"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use libp2p::identity::{ed25519, Keypair};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const DEFAULT_KEY_FILE: &str = "node.key";
+
+// The key file holds the raw 64 byte ed25519 keypair (secret followed by public key)
+#[derive(Debug)]
+pub enum KeyFileError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyFileError::Io(e) => write!(f, "io error: {}", e),
+            KeyFileError::Invalid(reason) => write!(f, "invalid key file: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for KeyFileError {
+    fn from(e: io::Error) -> Self {
+        KeyFileError::Io(e)
+    }
+}
+
+pub fn load_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyFileError> {
+    let mut bytes = fs::read(path)?;
+    ed25519::Keypair::decode(&mut bytes)
+        .map(Keypair::Ed25519)
+        .map_err(|e| KeyFileError::Invalid(e.to_string()))
+}
+
+pub fn save_keypair<P: AsRef<Path>>(keypair: &ed25519::Keypair, path: P) -> Result<(), KeyFileError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(&keypair.encode())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Loads the node key, or generates and saves a new one on first start,
+/// so the peer id stays the same across restarts.
+pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyFileError> {
+    let path = path.as_ref();
+    if path.exists() {
+        return load_keypair(path);
+    }
+    let keypair = ed25519::Keypair::generate();
+    save_keypair(&keypair, path)?;
+    Ok(Keypair::Ed25519(keypair))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keyfile::{load_keypair, load_or_generate};
+    use libp2p::PeerId;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_key_persists() {
+        let path = env::temp_dir().join(format!("elemchain-test-{}.key", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let generated = load_or_generate(&path).unwrap();
+        let loaded = load_or_generate(&path).unwrap();
+        assert_eq!(PeerId::from(generated.public()), PeerId::from(loaded.public()));
+
+        fs::write(&path, b"too short").unwrap();
+        assert!(load_keypair(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
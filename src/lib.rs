@@ -0,0 +1,66 @@
+//! A small proof of work blockchain.
+//!
+//! The core (blocks, transactions, validation, mining, chain params and the node state)
+//! builds without any networking. The `network` feature adds the libp2p node runtime,
+//! the `cli` feature the command line front end of the binary on top of it.
+
+pub mod archive;
+pub mod block;
+pub mod blockchain;
+pub mod compact_block;
+pub mod error;
+pub mod events;
+pub mod node;
+pub mod params;
+pub mod transaction;
+
+#[cfg(feature = "network")]
+pub mod address_book;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+pub mod config;
+#[cfg(feature = "cli")]
+pub mod daemon;
+#[cfg(feature = "network")]
+pub mod dandelion;
+#[cfg(feature = "network")]
+pub mod discovery;
+#[cfg(feature = "network")]
+pub mod gossip;
+#[cfg(feature = "network")]
+pub mod handshake;
+#[cfg(feature = "network")]
+pub mod http;
+#[cfg(feature = "network")]
+pub mod keyfile;
+#[cfg(feature = "cli")]
+pub mod logger;
+#[cfg(feature = "network")]
+pub mod message;
+#[cfg(feature = "network")]
+pub mod metrics;
+#[cfg(feature = "network")]
+pub mod miner;
+#[cfg(feature = "network")]
+pub mod p2p;
+#[cfg(feature = "network")]
+pub mod peer_manager;
+#[cfg(feature = "network")]
+pub mod protocol;
+#[cfg(feature = "network")]
+pub mod rest;
+#[cfg(feature = "network")]
+pub mod rpc;
+#[cfg(feature = "network")]
+pub mod sync;
+#[cfg(feature = "network")]
+pub mod ws;
+
+pub use block::{Block, BlockHeader};
+pub use blockchain::Blockchain;
+pub use error::{NodeError, TransactionError, ValidationError};
+pub use events::{Event, EventBus};
+pub use node::{Node, Resolution};
+pub use params::ChainParams;
+pub use transaction::Transaction;
@@ -0,0 +1,117 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::json;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `key=value` pairs.
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {}", other)),
+        }
+    }
+}
+
+// Writes one line per record to stderr, so stdout stays free for command output.
+// Records of other crates (libp2p and friends) only show up from warnings on.
+pub struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(Logger { level, format }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record, time: SystemTime) -> String {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let ts = format!("{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis());
+        let level = record.level().as_str().to_lowercase();
+        let msg = record.args().to_string();
+        match self.format {
+            LogFormat::Text => format!("ts={} level={} target={} msg={:?}", ts, level, record.target(), msg),
+            LogFormat::Json => {
+                json!({ "ts": ts, "level": level, "target": record.target(), "msg": msg }).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = match metadata.target().starts_with("elemchain") {
+            true => self.level,
+            false => self.level.min(LevelFilter::Warn),
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record, SystemTime::now());
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logger::{LogFormat, Logger};
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_format() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_640_995_200_042);
+        let args = format_args!("banning {} after \"{}\"", "peer", "spam");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Warn)
+            .target("elemchain::p2p")
+            .build();
+
+        let text = Logger {
+            level: LevelFilter::Info,
+            format: LogFormat::Text,
+        };
+        assert_eq!(
+            text.format(&record, time),
+            r#"ts=1640995200.042 level=warn target=elemchain::p2p msg="banning peer after \"spam\"""#
+        );
+
+        let json = Logger {
+            level: LevelFilter::Info,
+            format: LogFormat::Json,
+        };
+        let line: serde_json::Value = serde_json::from_str(&json.format(&record, time)).unwrap();
+        assert_eq!(line["msg"], "banning peer after \"spam\"");
+        assert_eq!(line["level"], "warn");
+
+        // Chatty dependencies are held back to warnings
+        let debug = Logger {
+            level: LevelFilter::Debug,
+            format: LogFormat::Text,
+        };
+        let metadata = |target| Metadata::builder().level(Level::Debug).target(target).build();
+        assert!(debug.enabled(&metadata("elemchain::sync")));
+        assert!(!debug.enabled(&metadata("libp2p_gossipsub")));
+    }
+}
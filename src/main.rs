@@ -1,167 +1,271 @@
-mod block;
-mod blockchain;
-mod node;
-mod p2p;
-mod transaction;
-
-use blockchain::Blockchain;
-use dialoguer::{theme::ColorfulTheme, Select};
-use tokio::sync::mpsc::{UnboundedSender};
-use p2p::{AppBehaviour, ChainResponse};
-use rand::seq::SliceRandom;
-use std::time::{Duration, SystemTime};
-use std::{
-    thread,
-};
-use transaction::Transaction;
-
-use libp2p::{
-    core::upgrade,
-    futures::StreamExt,
-    identity, mplex,
-    noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
-    tcp::TokioTcpConfig,
-    PeerId, Transport,
+use dialoguer::{theme::ColorfulTheme, Input, Select};
+use elemchain::{
+    archive,
+    cli::{self, Cli, Command},
+    daemon,
+    error::NodeError,
+    logger::Logger,
+    metrics,
+    miner::MiningMode,
+    p2p, rest, rpc, sync, ws, Blockchain, Transaction,
 };
+use libp2p::{futures::StreamExt, PeerId};
+use log::info;
+use rand::seq::SliceRandom;
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, fs, io, process, thread};
 use tokio::{
-    select, spawn,
-    sync::{mpsc},
+    net::TcpListener,
+    select,
+    sync::{broadcast, mpsc, oneshot},
+    time::interval,
 };
 
 pub fn handle_print_chain(chain: &Blockchain) {
     println!("{}", chain);
 }
 
-pub async fn swarm_factory(
-    node: node::Node,
-    rsp_sender: UnboundedSender<ChainResponse>,
-    ) -> SwarmBuilder<AppBehaviour> {
-    let id_keys = identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(id_keys.public());
+// Arguments of menu options which need more input, collected in the menu thread
+pub enum MenuArgs {
+    Archive(ArchiveArgs),
+    Dial(String),
+}
 
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&id_keys)
-        .expect("can create auth keys");
+pub struct ArchiveArgs {
+    pub path: String,
+    pub from: usize,
+    pub to: Option<usize>,
+}
 
-    let transp = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
+fn prompt_archive_args(export: bool) -> io::Result<ArchiveArgs> {
+    let theme = ColorfulTheme::default();
+    let path: String = Input::with_theme(&theme)
+        .with_prompt("Archive file path")
+        .default(String::from("chain.archive"))
+        .interact_text()?;
+    if !export {
+        return Ok(ArchiveArgs {
+            path,
+            from: 0,
+            to: None,
+        });
+    }
+    let from: usize = Input::with_theme(&theme)
+        .with_prompt("From height")
+        .default(0)
+        .interact_text()?;
+    let to: String = Input::with_theme(&theme)
+        .with_prompt("To height (empty for tip)")
+        .allow_empty(true)
+        .interact_text()?;
+    Ok(ArchiveArgs {
+        path,
+        from,
+        to: to.trim().parse().ok(),
+    })
+}
 
-    let behaviour =
-        p2p::AppBehaviour::new(peer_id, node, rsp_sender).await;
+fn prompt_dial_addr() -> io::Result<String> {
+    Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Peer multiaddr, e.g. /ip4/10.0.0.2/tcp/4001/p2p/<peer id>")
+        .interact_text()
+}
 
-    let swarm = SwarmBuilder::new(transp, behaviour, peer_id).executor(Box::new(|fut| {
-        spawn(fut);
-    }));
+/// Asks for a menu option, and for whatever more input it needs.
+fn prompt_menu(selections: &[&str], wallet: &PeerId) -> io::Result<(usize, Option<MenuArgs>)> {
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .clear(true)
+        .with_prompt(format!("Your wallet num is {}\r\nPick option\r\n", wallet))
+        .default(0)
+        .items(selections)
+        .interact()?;
 
-    swarm
+    let menu_args = match selection {
+        5 => Some(MenuArgs::Archive(prompt_archive_args(true)?)),
+        6 => Some(MenuArgs::Archive(prompt_archive_args(false)?)),
+        7 => Some(MenuArgs::Dial(prompt_dial_addr()?)),
+        _ => None,
+    };
+    Ok((selection, menu_args))
 }
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::from_args(env::args().skip(1), env::vars()).unwrap_or_else(|e| {
+        eprintln!("{}", NodeError::Config(e));
+        eprintln!("{}", cli::USAGE);
+        process::exit(2);
+    });
+
+    Logger::init(cli.log_level(), cli.log_format).expect("logger is only set once");
+    if matches!(cli.command, Command::Run { .. } | Command::WalletNew) {
+        if let Err(e) = fs::create_dir_all(&cli.config.data_dir) {
+            eprintln!("could not create data directory {}: {}", cli.config.data_dir.display(), e);
+            process::exit(1);
+        }
+    }
+
+    let result = match cli.command {
+        Command::Run { menu } => run_node(&cli, menu).await.map(|()| None),
+        _ => cli::execute(&cli).await.map(Some),
+    };
+    match result {
+        Ok(None) => {}
+        Ok(Some(output)) => output.print(cli.json),
+        Err(e) => {
+            match cli.json {
+                true => println!("{}", serde_json::json!({ "error": e.to_string() })),
+                false => eprintln!("{}", e),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+async fn run_node(cli: &Cli, menu: bool) -> Result<(), NodeError> {
+    let config = &cli.config.network;
     let selections = &[
         "Create block",
         "View local blockchain",
         "Generate transaction",
         "View nodes",
         "View pending txs",
+        "Export chain",
+        "Import chain",
+        "Dial peer",
+        "Peer info",
+        "Start or stop mining",
     ];
 
-    let blockchain = Blockchain::new(0, 3, 256);
-    let node = node::Node { 
-        blockchain: blockchain,
-        last_time_synced: 0.0,
+    // Held until we return, so no second node starts on the same files
+    let _pid_file = match &cli.config.pid_file {
+        Some(path) => Some(daemon::PidFile::acquire(path)?),
+        None => None,
     };
-    let mut pending_txs: Vec<Transaction> = vec![];
 
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
-    let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
+    let mut node = daemon::open_node(&cli.config)?;
+    let (events, _) = broadcast::channel(ws::EVENT_BUFFER);
+    if cli.config.ws_addr.is_some() {
+        let events = events.clone();
+        node.events.subscribe(move |event| {
+            // Fails only while nobody is connected
+            let _ = events.send(event.clone());
+        });
+    }
+
+    let mut sync_timer = interval(sync::SYNC_INTERVAL);
 
     let (cli_sender, mut cli_rcv) = mpsc::unbounded_channel();
+    let (rpc_sender, mut rpc_rcv) = mpsc::unbounded_channel::<rpc::RpcCall>();
+    let (rest_sender, mut rest_rcv) = mpsc::unbounded_channel::<rest::RestCall>();
+    let (scrape_sender, mut scrape_rcv) = mpsc::unbounded_channel::<oneshot::Sender<String>>();
 
-    let mut swarm = swarm_factory(node, response_sender)
-        .await
-        .build();
+    let mut swarm = p2p::start_swarm(node, config).await?;
+    if cli.config.mine {
+        swarm.behaviour_mut().miner.start(None);
+    }
+    let shutdown = daemon::shutdown_signal();
+    tokio::pin!(shutdown);
 
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
+    match cli.config.rpc_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("rpc listening on {}", addr);
+            tokio::spawn(rpc::serve(listener, rpc_sender));
+        }
+        // Same as the menu, no sender means the rpc branch never fires
+        None => drop(rpc_sender),
+    }
+    match cli.config.rest_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("rest api listening on {}", addr);
+            tokio::spawn(rest::serve(listener, rest_sender));
+        }
+        None => drop(rest_sender),
+    }
+    if let Some(addr) = cli.config.ws_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("websocket listening on {}", addr);
+        tokio::spawn(ws::serve(listener, events));
+    }
+    match cli.config.metrics_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("metrics listening on {}", addr);
+            tokio::spawn(metrics::serve(listener, scrape_sender));
+        }
+        None => drop(scrape_sender),
+    }
 
- 
     // Wallet num is peer id
     let wallen_num = swarm.behaviour().peer_id;
-    thread::spawn(move || loop {
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .clear(true)
-            .with_prompt(format!("Your wallet num is {}\r\nPick option\r\n", wallen_num))
-            .default(0)
-            .items(&selections[..])
-            .interact()
-            .unwrap();
-
-        cli_sender.send(selection).unwrap();
-
-        // block sync only on interaction
-        init_sender.send(true).expect("can send msg to init channel");
-
-    });
+    if !menu {
+        // Nothing will ever come from the menu, which disables its branch below
+        drop(cli_sender);
+        info!("node {} running", wallen_num);
+    } else {
+        thread::spawn(move || loop {
+            match prompt_menu(&selections[..], &wallen_num) {
+                Ok(picked) => {
+                    // The event loop is gone, the node is shutting down
+                    if cli_sender.send(picked).is_err() {
+                        break;
+                    }
+                }
+                // Without a terminal the node keeps running, just without the menu
+                Err(e) => {
+                    eprintln!("menu closed: {}", NodeError::Io(e));
+                    break;
+                }
+            }
+        });
+    }
 
     loop {
         let mut selection = 99;
+        let mut menu_args = None;
         let evt = {
             select! {
-                response = response_rcv.recv() => {
-                    Some(p2p::EventType::LocalChainResponse(response.expect("response exists")))
+                _ = &mut shutdown => break,
+                _tick = sync_timer.tick() => {
+                    Some(p2p::EventType::Sync)
                 },
-                _init = init_rcv.recv() => {
-                    Some(p2p::EventType::Init)
-                },
-                _selection = cli_rcv.recv() => {
-                    selection = _selection.unwrap();
+                Some(_selection) = cli_rcv.recv() => {
+                    (selection, menu_args) = _selection;
                     Some(p2p::EventType::Cli)
 
                 },
-                _event = swarm.select_next_some() => {
+                Some(call) = rpc_rcv.recv() => {
+                    rpc::answer(swarm.behaviour_mut(), call);
+                    None
+                },
+                Some(call) = rest_rcv.recv() => {
+                    let _ = call.reply.send(rest::handle_get(swarm.behaviour(), &call.path));
                     None
                 },
+                Some(reply) = scrape_rcv.recv() => {
+                    let _ = reply.send(metrics::render(swarm.behaviour()));
+                    None
+                },
+                event = swarm.select_next_some() => {
+                    p2p::handle_swarm_event(&mut swarm, event)
+                },
             }
         };
 
         if let Some(event) = evt {
             match event {
-                p2p::EventType::Init => {
-                    let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                    let peers = p2p::get_list_peers(&swarm);
-                    if !peers.is_empty() {
-                        let req = p2p::LocalChainRequest {
-                            from_peer_id: peers
-                                .iter()
-                                .last()
-                                .expect("at least one peer")
-                                .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .publish(topic, json.as_bytes());
+                p2p::EventType::Sync => {
+                    if swarm.behaviour().connected_peers().is_empty() {
+                        p2p::reconnect_known_peers(&mut swarm);
                     }
-                }
-                p2p::EventType::LocalChainResponse(resp) => {
-                    let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(topic, json.as_bytes());
+                    swarm.behaviour_mut().save_address_book();
+                    swarm.behaviour_mut().peer_manager.prune(Instant::now());
+                    daemon::save_chain(&swarm, &cli.config);
+                    swarm.behaviour_mut().bootstrap_discovery();
+                    swarm.behaviour_mut().start_sync();
+                    swarm.behaviour_mut().fluff_expired();
                 }
                 p2p::EventType::Cli => {
                     // let selection = cli_rcv.recv().await.unwrap();
@@ -169,26 +273,18 @@ async fn main() {
                         clearscreen::clear().expect("failed to clear screen");
                         thread::sleep(Duration::from_millis(100));
 
-                        let suc = swarm
-                            .behaviour_mut()
-                            .node
-                            .blockchain
-                            .try_mine(pending_txs.clone());
-                        if suc {
-                            // IF successfull mining, then we broadcast the block to the network
-                            // https://www.oreilly.com/library/view/mastering-bitcoin/9781491902639/ch08.html
-                            // However, there is no complex logic like orphans blocks or mempool here yet.
-                            pending_txs.clear();
-                            let topic = swarm.behaviour_mut().blockchain_topic.clone();
-                            let json = serde_json::to_string(
-                                &swarm.behaviour_mut().node.blockchain.chain.last(),
-                            )
-                            .expect("can jsonify request");
-
-                            swarm
-                                .behaviour_mut()
-                                .floodsub
-                                .publish(topic, json.as_bytes());
+                        // Found on the worker, then pushed to the network like any mined block,
+                        // peers which miss its parent fetch the rest themselves
+                        // https://www.oreilly.com/library/view/mastering-bitcoin/9781491902639/ch08.html
+                        // A running miner keeps its mode, it mines the next block anyway
+                        let miner = &mut swarm.behaviour_mut().miner;
+                        match miner.mode() {
+                            MiningMode::Stopped => {
+                                miner.start(Some(1));
+                                println!("\nMining a block in the background\n");
+                            }
+                            MiningMode::Blocks(blocks) => println!("\nAlready mining {} more blocks\n", blocks),
+                            MiningMode::Continuous => println!("\nAlready mining until stopped\n"),
                         }
                     }
                     if selection == 1 {
@@ -196,48 +292,149 @@ async fn main() {
                         thread::sleep(Duration::from_millis(100));
                         print!("Last time from syncing chains: {}. \r\n\r\n", &swarm.behaviour_mut().node.last_time_synced);
                         handle_print_chain(&swarm.behaviour_mut().node.blockchain);
-                        print!("\n");
+                        println!();
                     }
                     if selection == 2 {
                         clearscreen::clear().expect("failed to clear screen");
 
                         // We will send 100 coins to random peer
                         let peers = p2p::get_list_peers(&swarm);
-                        let to = peers.choose(&mut rand::thread_rng());
+                        let to = match peers.choose(&mut rand::thread_rng()) {
+                            Some(to) => to.to_string(),
+                            None => {
+                                println!("{}", NodeError::Command(String::from("no peers to send to")));
+                                continue;
+                            }
+                        };
 
                         let transaction = Transaction {
                             from: wallen_num.to_string().clone(),
-                            to: to.unwrap().to_string(),
+                            to,
                             amount: 100,
                             time: SystemTime::now(),
                         };
                         thread::sleep(Duration::from_millis(100));
                         println!("Generated tx \n {}", transaction);
 
-                        pending_txs.push(transaction);
+                        swarm.behaviour_mut().submit_transaction(transaction);
                     }
                     if selection == 3 {
                         clearscreen::clear().expect("failed to clear screen");
-                        let peers = p2p::get_list_peers(&swarm);
+                        let behaviour = swarm.behaviour();
+                        let peers = behaviour.connected_peers();
                         thread::sleep(Duration::from_millis(100));
-                        print!("Peers len {}. Peers list: \r\n", peers.len());
-                        for mut peer in peers {
-                            peer = peer.split_whitespace().collect();
-                            print!("{}\r\n", peer);
+                        print!(
+                            "Peers len {} ({} in address book). Peers list: \r\n",
+                            peers.len(),
+                            behaviour.address_book.len()
+                        );
+                        for peer in peers {
+                            if let Some(handshake) = behaviour.peer_handshake(&peer) {
+                                print!("{} height {} {}\r\n", peer, handshake.best_height, handshake.user_agent);
+                            }
                         }
-                        print!("\n");
+                        println!();
                     }
                     if selection == 4 {
                         clearscreen::clear().expect("failed to clear screen");
                         thread::sleep(Duration::from_millis(100));
+                        let pending_txs = &swarm.behaviour().node.pending_txs;
                         print!("Total txs {}. Tx list: \r\n", pending_txs.len());
-                        for i in 0..pending_txs.len() {
-                            print!("{}. {} \r\n", i + 1, &pending_txs[i]);
+                        for (i, tx) in pending_txs.iter().enumerate() {
+                            print!("{}. {} \r\n", i + 1, tx);
+                        }
+                        println!();
+                    }
+                    if selection == 5 {
+                        let args = match menu_args.take() {
+                            Some(MenuArgs::Archive(args)) => args,
+                            _ => unreachable!("export args are collected with selection"),
+                        };
+                        let chain = &swarm.behaviour().node.blockchain;
+                        let to = args.to.unwrap_or_else(|| chain.len().saturating_sub(1));
+                        match archive::export_to_file(chain, args.from, to, &args.path) {
+                            Ok(count) => print!("Exported {} blocks to {}\r\n", count, args.path),
+                            Err(e) => print!("Export failed: {}\r\n", e),
+                        }
+                        println!();
+                    }
+                    if selection == 6 {
+                        let args = match menu_args.take() {
+                            Some(MenuArgs::Archive(args)) => args,
+                            _ => unreachable!("import args are collected with selection"),
+                        };
+                        match archive::import_from_file(&mut swarm.behaviour_mut().node, &args.path) {
+                            Ok(summary) if summary.adopted => print!(
+                                "Imported {} blocks starting at height {}\r\n",
+                                summary.imported, summary.start_height
+                            ),
+                            Ok(_) => print!("Archive is valid, but local chain is longer. Kept local chain\r\n"),
+                            Err(e) => print!("Import failed: {}\r\n", e),
+                        }
+                        println!();
+                    }
+                    if selection == 7 {
+                        if let Some(MenuArgs::Dial(addr)) = menu_args.take() {
+                            match addr.trim().parse() {
+                                Ok(addr) => {
+                                    p2p::dial(&mut swarm, &addr);
+                                    print!("Dialing {}\r\n", addr);
+                                }
+                                Err(e) => print!("Invalid multiaddr {}: {}\r\n", addr, e),
+                            }
+                        }
+                        println!();
+                    }
+                    if selection == 8 {
+                        clearscreen::clear().expect("failed to clear screen");
+                        thread::sleep(Duration::from_millis(100));
+                        let now = Instant::now();
+                        let behaviour = swarm.behaviour_mut();
+                        for peer in behaviour.connected_peers() {
+                            let handshake = behaviour.peer_handshake(&peer);
+                            if let Some(info) = behaviour.peer_manager.peer_info(&peer) {
+                                print!("{}\r\n", peer);
+                                print!("  address: {}\r\n", info.addr);
+                                if let Some(handshake) = handshake {
+                                    print!("  agent: {}, height: {}\r\n", handshake.user_agent, handshake.best_height);
+                                }
+                                print!(
+                                    "  connected for {}s, {} requests, misbehavior score {}",
+                                    now.duration_since(info.connected_since).as_secs(),
+                                    info.total_requests,
+                                    behaviour.peer_manager.score(&peer, now)
+                                );
+                                match info.last_offense {
+                                    Some(offense) => print!(" (last: {})\r\n", offense),
+                                    None => print!("\r\n"),
+                                }
+                            }
+                        }
+                        let bans = behaviour.peer_manager.bans(now);
+                        print!("Banned peers {}\r\n", bans.len());
+                        for (peer, remaining) in bans {
+                            print!("{} for another {}s\r\n", peer, remaining.as_secs());
+                        }
+                        println!();
+                    }
+                    if selection == 9 {
+                        clearscreen::clear().expect("failed to clear screen");
+                        thread::sleep(Duration::from_millis(100));
+                        let miner = &mut swarm.behaviour_mut().miner;
+                        if miner.mode() == MiningMode::Stopped {
+                            miner.start(None);
+                            print!("Mining until stopped\r\n");
+                        } else {
+                            miner.stop();
+                            print!("Stopped mining\r\n");
                         }
-                        print!("\n");
+                        println!();
                     }
                 }
             }
         }
     }
+    info!("shutting down");
+    daemon::shutdown(&mut swarm, &cli.config).await;
+    Ok(())
 }
@@ -0,0 +1,266 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{
+    block::{encoded_size, Block, MAX_BLOCK_SIZE},
+    compact_block::CompactBlock,
+    handshake::Handshake,
+    protocol::{ChainRequest, ChainResponse},
+    sync::TipAnnouncement,
+    transaction::Transaction,
+};
+
+/// Version of the envelope layout, sent as the first byte of every message.
+pub const MESSAGE_VERSION: u8 = 1;
+
+// Encoded size limits per message kind. Anything above the largest of them is refused before
+// parsing, the limit of its kind is checked right after.
+pub const MAX_HANDSHAKE_SIZE: usize = 4 * 1024;
+pub const MAX_TRANSACTION_SIZE: usize = 4 * 1024;
+pub const MAX_TIP_SIZE: usize = 4 * 1024;
+pub const MAX_COMPACT_BLOCK_SIZE: usize = 256 * 1024;
+pub const MAX_REQUEST_SIZE: usize = 512 * 1024;
+pub const MAX_HEADERS_RESPONSE_SIZE: usize = 2 * 1024 * 1024;
+pub const MAX_TRANSACTIONS_RESPONSE_SIZE: usize = 2 * 1024 * 1024;
+/// A chunk of a block range holds blocks adding up to at most `MAX_BLOCK_SIZE`,
+/// or a single block of up to that size, plus the envelope around it.
+pub const MAX_CHUNK_SIZE: usize = MAX_BLOCK_SIZE + 16 * 1024;
+/// Largest single frame of any kind.
+pub const MAX_FRAME_SIZE: usize = MAX_TRANSACTIONS_RESPONSE_SIZE;
+
+// Every message on the wire, gossiped or sent over request-response, is one of these.
+// The kind is an explicit tag, so adding a kind can never make an old one decode differently.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", content = "payload")]
+pub enum NetworkMessage {
+    Handshake(Handshake),
+    /// A freshly mined block, pushed by its miner.
+    Block { height: usize, block: Block },
+    /// A freshly mined block as its header and short transaction ids.
+    CompactBlock(CompactBlock),
+    Transaction(Transaction),
+    TipAnnouncement(TipAnnouncement),
+    Request(ChainRequest),
+    Response(ChainResponse),
+    /// Part of a block range sent as a stream of chunks. An empty chunk ends the stream.
+    BlockChunk(Vec<Block>),
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Empty,
+    UnsupportedVersion(u8),
+    UnknownKind(String),
+    /// A message of a known kind which is not what the protocol expects at that point.
+    UnexpectedKind(&'static str),
+    TooLarge { kind: &'static str, size: usize, max: usize },
+    Malformed(serde_json::Error),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::Empty => write!(f, "empty message"),
+            MessageError::UnsupportedVersion(v) => write!(f, "unsupported message version {}", v),
+            MessageError::UnknownKind(kind) => write!(f, "unknown message kind {:?}", kind),
+            MessageError::UnexpectedKind(kind) => write!(f, "unexpected message kind {}", kind),
+            MessageError::TooLarge { kind, size, max } => {
+                write!(f, "{} message of {} bytes exceeds the limit of {}", kind, size, max)
+            }
+            MessageError::Malformed(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+// Only used to tell an unknown kind from a broken known one
+#[derive(Deserialize)]
+struct Kind {
+    kind: String,
+}
+
+const KINDS: &[&str] = &[
+    "Handshake",
+    "Block",
+    "CompactBlock",
+    "Transaction",
+    "TipAnnouncement",
+    "Request",
+    "Response",
+    "BlockChunk",
+];
+
+impl NetworkMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetworkMessage::Handshake(_) => "Handshake",
+            NetworkMessage::Block { .. } => "Block",
+            NetworkMessage::CompactBlock(_) => "CompactBlock",
+            NetworkMessage::Transaction(_) => "Transaction",
+            NetworkMessage::TipAnnouncement(_) => "TipAnnouncement",
+            NetworkMessage::Request(_) => "Request",
+            NetworkMessage::Response(_) => "Response",
+            NetworkMessage::BlockChunk(_) => "BlockChunk",
+        }
+    }
+
+    /// Largest encoded size allowed for this message.
+    pub fn max_size(&self) -> usize {
+        match self {
+            NetworkMessage::Handshake(_) => MAX_HANDSHAKE_SIZE,
+            NetworkMessage::Block { .. } => MAX_CHUNK_SIZE,
+            NetworkMessage::CompactBlock(_) => MAX_COMPACT_BLOCK_SIZE,
+            NetworkMessage::Transaction(_) | NetworkMessage::Response(ChainResponse::Ack) => MAX_TRANSACTION_SIZE,
+            NetworkMessage::TipAnnouncement(_) => MAX_TIP_SIZE,
+            NetworkMessage::Request(_) => MAX_REQUEST_SIZE,
+            NetworkMessage::Response(ChainResponse::Headers(_)) => MAX_HEADERS_RESPONSE_SIZE,
+            NetworkMessage::Response(ChainResponse::Transactions(_)) => MAX_TRANSACTIONS_RESPONSE_SIZE,
+            // Larger block ranges are streamed in chunks
+            NetworkMessage::Response(ChainResponse::Blocks(_))
+            | NetworkMessage::Response(ChainResponse::BlockTransactions { .. })
+            | NetworkMessage::BlockChunk(_) => MAX_CHUNK_SIZE,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![MESSAGE_VERSION];
+        serde_json::to_writer(&mut data, self).expect("can jsonify message");
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, MessageError> {
+        let (version, body) = data.split_first().ok_or(MessageError::Empty)?;
+        if *version != MESSAGE_VERSION {
+            return Err(MessageError::UnsupportedVersion(*version));
+        }
+        if data.len() > MAX_FRAME_SIZE {
+            return Err(MessageError::TooLarge {
+                kind: "unparsed",
+                size: data.len(),
+                max: MAX_FRAME_SIZE,
+            });
+        }
+        let message: NetworkMessage =
+            serde_json::from_slice(body).map_err(|e| match serde_json::from_slice::<Kind>(body) {
+                Ok(Kind { kind }) if !KINDS.contains(&kind.as_str()) => MessageError::UnknownKind(kind),
+                _ => MessageError::Malformed(e),
+            })?;
+        message.check_size(data.len())?;
+        Ok(message)
+    }
+
+    pub fn check_size(&self, size: usize) -> Result<(), MessageError> {
+        if size > self.max_size() {
+            return Err(MessageError::TooLarge {
+                kind: self.kind(),
+                size,
+                max: self.max_size(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Splits blocks into chunks of at most `MAX_BLOCK_SIZE` encoded bytes each,
+/// a larger block gets a chunk of its own.
+pub fn chunk_blocks(blocks: Vec<Block>) -> Vec<Vec<Block>> {
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut chunk_size = 0;
+    for block in blocks {
+        let size = encoded_size(&block);
+        if !chunk.is_empty() && chunk_size + size > MAX_BLOCK_SIZE {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_size = 0;
+        }
+        chunk_size += size;
+        chunk.push(block);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::{encoded_size, tests::generate_blocks, MAX_BLOCK_SIZE};
+    use crate::message::{
+        chunk_blocks, MessageError, NetworkMessage, KINDS, MAX_FRAME_SIZE, MAX_TRANSACTION_SIZE, MESSAGE_VERSION,
+    };
+    use crate::protocol::ChainRequest;
+
+    #[test]
+    fn test_roundtrip() {
+        let block = generate_blocks().remove(0);
+        let messages = vec![
+            NetworkMessage::Transaction(block.transactions[0].clone()),
+            NetworkMessage::Request(ChainRequest::GetBlocks {
+                hashes: vec![block.hash.clone()],
+            }),
+            NetworkMessage::Block { height: 1, block },
+        ];
+        for message in messages {
+            let data = message.encode();
+            assert_eq!(data[0], MESSAGE_VERSION);
+            assert_eq!(NetworkMessage::decode(&data).unwrap(), message);
+            assert!(KINDS.contains(&message.kind()));
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(NetworkMessage::decode(&[]), Err(MessageError::Empty)));
+
+        let mut data = NetworkMessage::Request(ChainRequest::GetHeaders { locator: vec![] }).encode();
+        data[0] = MESSAGE_VERSION + 1;
+        assert!(matches!(
+            NetworkMessage::decode(&data),
+            Err(MessageError::UnsupportedVersion(_))
+        ));
+
+        let mut unknown = vec![MESSAGE_VERSION];
+        unknown.extend_from_slice(br#"{"kind":"Gossip","payload":{}}"#);
+        assert!(matches!(
+            NetworkMessage::decode(&unknown),
+            Err(MessageError::UnknownKind(kind)) if kind == "Gossip"
+        ));
+
+        let mut broken = vec![MESSAGE_VERSION];
+        broken.extend_from_slice(br#"{"kind":"Transaction","payload":{}}"#);
+        assert!(matches!(NetworkMessage::decode(&broken), Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let mut tx = generate_blocks()[0].transactions[0].clone();
+        tx.from = "a".repeat(MAX_TRANSACTION_SIZE);
+        let data = NetworkMessage::Transaction(tx).encode();
+        assert!(matches!(
+            NetworkMessage::decode(&data),
+            Err(MessageError::TooLarge { kind: "Transaction", .. })
+        ));
+
+        // Not even parsed, garbage is refused just the same
+        let mut huge = vec![MESSAGE_VERSION];
+        huge.resize(MAX_FRAME_SIZE + 1, b'x');
+        assert!(matches!(
+            NetworkMessage::decode(&huge),
+            Err(MessageError::TooLarge { kind: "unparsed", .. })
+        ));
+    }
+
+    #[test]
+    fn test_chunk_blocks() {
+        let block = generate_blocks().remove(0);
+        let per_chunk = MAX_BLOCK_SIZE / encoded_size(&block);
+        let blocks = vec![block; per_chunk * 2 + 1];
+
+        let chunks = chunk_blocks(blocks.clone());
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| encoded_size(chunk) <= MAX_BLOCK_SIZE + chunk.len()));
+        assert_eq!(chunks.concat(), blocks);
+        assert!(chunk_blocks(vec![]).is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    block::encoded_size,
+    events::Event,
+    http::{self, Response},
+    node::Node,
+    p2p::AppBehaviour,
+};
+
+pub const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9464";
+/// Blocks the block interval and the hash rate are averaged over.
+pub const BLOCK_WINDOW: usize = 10;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Counted from the node's events, so from wherever the change came
+#[derive(Debug, Default)]
+pub struct EventCounters {
+    pub blocks_connected: AtomicU64,
+    pub blocks_disconnected: AtomicU64,
+    pub txs_accepted: AtomicU64,
+    pub txs_evicted: AtomicU64,
+}
+
+/// Counters kept while the node runs. Everything else is read from the node when scraped.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Messages by kind, gossip and requests alike.
+    pub received: BTreeMap<&'static str, u64>,
+    pub sent: BTreeMap<&'static str, u64>,
+    /// Misbehavior of peers by offense.
+    pub invalid: BTreeMap<String, u64>,
+    pub events: Arc<EventCounters>,
+}
+
+impl Metrics {
+    /// Metrics counting the events of `node`.
+    pub fn new(node: &mut Node) -> Self {
+        let metrics = Metrics::default();
+        let counters = metrics.events.clone();
+        node.events.subscribe(move |event| {
+            let counter = match event {
+                Event::BlockConnected { .. } => &counters.blocks_connected,
+                Event::BlockDisconnected { .. } => &counters.blocks_disconnected,
+                Event::TxAccepted(_) => &counters.txs_accepted,
+                Event::TxEvicted { .. } => &counters.txs_evicted,
+                _ => return,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        metrics
+    }
+
+    pub fn received(&mut self, kind: &'static str) {
+        *self.received.entry(kind).or_default() += 1;
+    }
+
+    pub fn sent(&mut self, kind: &'static str) {
+        *self.sent.entry(kind).or_default() += 1;
+    }
+
+    pub fn invalid(&mut self, offense: &str) {
+        *self.invalid.entry(offense.replace(' ', "_")).or_default() += 1;
+    }
+}
+
+/// Seconds between blocks and expected hashes per second, over the last `BLOCK_WINDOW` blocks.
+pub fn block_rate(node: &Node) -> Option<(f64, f64)> {
+    let chain = &node.blockchain.chain;
+    // The genesis block's time is made up
+    let first = chain.len().saturating_sub(BLOCK_WINDOW + 1).max(1);
+    let window = chain.get(first..)?;
+    let (oldest, newest) = (window.first()?, window.last()?);
+    let span = newest.time.duration_since(oldest.time).ok()?.as_secs_f64();
+    if window.len() < 2 || span <= 0.0 {
+        return None;
+    }
+    let work: u128 = window[1..].iter().map(|block| block.header().work()).sum();
+    Some((span / (window.len() - 1) as f64, work as f64 / span))
+}
+
+struct Writer(String);
+
+impl Writer {
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP elemchain_{} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE elemchain_{} {}", name, kind);
+    }
+
+    fn value<V: std::fmt::Display>(&mut self, name: &str, labels: &str, value: V) {
+        let _ = writeln!(self.0, "elemchain_{}{} {}", name, labels, value);
+    }
+
+    fn single<V: std::fmt::Display>(&mut self, name: &str, kind: &str, help: &str, value: V) {
+        self.metric(name, kind, help);
+        self.value(name, "", value);
+    }
+
+    fn labeled<K: std::fmt::Display>(&mut self, name: &str, help: &str, label: &str, values: &BTreeMap<K, u64>) {
+        self.metric(name, "counter", help);
+        for (key, value) in values {
+            self.value(name, &format!("{{{}=\"{}\"}}", label, key), value);
+        }
+    }
+}
+
+/// The node's metrics in the Prometheus text format.
+pub fn render(behaviour: &AppBehaviour) -> String {
+    let node = &behaviour.node;
+    let chain = &node.blockchain;
+    let metrics = &behaviour.metrics;
+    let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = Writer(String::new());
+
+    out.single("chain_height", "gauge", "Height of the best chain tip.", chain.len() - 1);
+    out.single("chain_work", "gauge", "Cumulative work of the best chain.", chain.total_work());
+    if let Some(tip) = chain.chain.last() {
+        let time = tip.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        out.single("tip_timestamp_seconds", "gauge", "Time the tip block was mined.", time);
+    }
+    if let Some((interval, hash_rate)) = block_rate(node) {
+        let help = format!("Average time between the last {} blocks.", BLOCK_WINDOW);
+        out.single("block_interval_seconds", "gauge", &help, interval);
+        let help = format!("Hashes per second the last {} blocks took, network wide.", BLOCK_WINDOW);
+        out.single("hash_rate", "gauge", &help, hash_rate);
+    }
+    let events = &metrics.events;
+    let connected = counter(&events.blocks_connected);
+    out.single("blocks_connected_total", "counter", "Blocks that joined the best chain.", connected);
+    let disconnected = counter(&events.blocks_disconnected);
+    out.single("blocks_disconnected_total", "counter", "Blocks that left the best chain.", disconnected);
+
+    let pending = &node.pending_txs;
+    let bytes: usize = pending.iter().map(encoded_size).sum();
+    out.single("mempool_transactions", "gauge", "Transactions waiting to be mined.", pending.len());
+    out.single("mempool_bytes", "gauge", "Encoded size of the waiting transactions.", bytes);
+    let accepted = counter(&events.txs_accepted);
+    out.single("mempool_accepted_total", "counter", "Transactions that entered the pending pool.", accepted);
+    let evicted = counter(&events.txs_evicted);
+    out.single("mempool_evicted_total", "counter", "Transactions that left the pending pool.", evicted);
+
+    out.single("peers", "gauge", "Peers which completed the handshake.", behaviour.connected_peers().len());
+    out.labeled("messages_received_total", "Messages received from peers by kind.", "kind", &metrics.received);
+    out.labeled("messages_sent_total", "Messages sent to peers by kind.", "kind", &metrics.sent);
+    out.labeled("invalid_messages_total", "Misbehavior of peers by offense.", "offense", &metrics.invalid);
+
+    out.metric("sync_state", "gauge", "1 for the state header sync is in.");
+    for state in ["idle", "headers", "bodies"] {
+        out.value("sync_state", &format!("{{state=\"{}\"}}", state), u8::from(behaviour.sync.state() == state));
+    }
+    let (downloaded, total) = behaviour.sync.progress().unwrap_or_default();
+    out.single("sync_blocks_downloaded", "gauge", "Block bodies the running sync downloaded.", downloaded);
+    out.single("sync_blocks_total", "gauge", "Block bodies the running sync downloads.", total);
+    out.0
+}
+
+/// Serves `GET /metrics` on `listener`, asking the node's event loop for the numbers.
+pub async fn serve(listener: TcpListener, scrapes: mpsc::UnboundedSender<oneshot::Sender<String>>) {
+    http::serve(listener, move |request| {
+        let scrapes = scrapes.clone();
+        async move {
+            if request.method != "GET" {
+                return Response::empty(405);
+            }
+            if request.path != "/metrics" {
+                return Response::empty(404);
+            }
+            let (reply, metrics) = oneshot::channel();
+            let metrics = match scrapes.send(reply) {
+                Ok(()) => metrics.await.ok(),
+                Err(_) => None,
+            };
+            match metrics {
+                Some(metrics) => Response {
+                    status: 200,
+                    headers: vec![(String::from("Content-Type"), String::from(CONTENT_TYPE))],
+                    body: metrics.into_bytes(),
+                },
+                None => Response::empty(503),
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::discovery::tests::local_swarm;
+    use crate::metrics::render;
+    use crate::params::ChainParams;
+
+    #[tokio::test]
+    async fn test_render() {
+        let (mut swarm, _) = local_swarm(ChainParams::test()).await;
+        let behaviour = swarm.behaviour_mut();
+        behaviour.submit_transaction(generate_blocks()[0].transactions[0].clone());
+        behaviour.mine_block().unwrap();
+        behaviour.mine_block().unwrap();
+        behaviour.metrics.invalid("invalid block");
+
+        let metrics = render(behaviour);
+        let value = |name: &str| {
+            metrics
+                .lines()
+                .find(|line| line.starts_with(&format!("elemchain_{} ", name)))
+                .and_then(|line| line.split(' ').nth(1))
+                .map(String::from)
+        };
+        assert_eq!(value("chain_height").as_deref(), Some("2"));
+        assert_eq!(value("blocks_connected_total").as_deref(), Some("2"));
+        assert_eq!(value("mempool_accepted_total").as_deref(), Some("1"));
+        assert_eq!(value("mempool_transactions").as_deref(), Some("0"));
+        assert!(value("block_interval_seconds").is_some());
+        assert!(metrics.contains("elemchain_invalid_messages_total{offense=\"invalid_block\"} 1"));
+        assert!(metrics.contains("elemchain_sync_state{state=\"idle\"} 1"));
+        assert!(metrics.contains("# TYPE elemchain_messages_sent_total counter"));
+    }
+}
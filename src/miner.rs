@@ -0,0 +1,223 @@
+use log::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    block::Block,
+    blockchain::MiningJob,
+    events::Event,
+    node::Node,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiningMode {
+    Stopped,
+    /// Stops after mining this many more blocks.
+    Blocks(u64),
+    Continuous,
+}
+
+// Mining happens on a worker thread, so the event loop keeps running meanwhile. Every
+// change to the tip or the pending pool bumps the epoch, which makes the worker drop its
+// job between rounds and the next `refresh` hand it one built on the new state.
+pub struct Miner {
+    mode: MiningMode,
+    epoch: Arc<AtomicU64>,
+    // Epoch the worker's job was built in, if it was given one since
+    job_epoch: Option<u64>,
+    jobs: std_mpsc::Sender<(u64, MiningJob)>,
+    found: mpsc::UnboundedReceiver<Block>,
+    // Heights and hashes mined since `generate`, sent on `done` once the last one is in
+    generated: Vec<(usize, String)>,
+    done: Option<oneshot::Sender<Vec<(usize, String)>>>,
+}
+
+impl Miner {
+    /// A stopped miner for `node`, with a worker thread waiting for jobs.
+    pub fn new(node: &mut Node) -> Self {
+        let epoch = Arc::new(AtomicU64::new(0));
+        let stale = epoch.clone();
+        node.events.subscribe(move |event| {
+            if let Event::BlockConnected { .. } | Event::BlockDisconnected { .. } | Event::TxAccepted(_) = event {
+                stale.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let (jobs, job_rcv) = std_mpsc::channel();
+        let (found_sender, found) = mpsc::unbounded_channel();
+        let current = epoch.clone();
+        thread::spawn(move || work(job_rcv, found_sender, current));
+
+        Miner {
+            mode: MiningMode::Stopped,
+            epoch,
+            job_epoch: None,
+            jobs,
+            found,
+            generated: vec![],
+            done: None,
+        }
+    }
+
+    pub fn mode(&self) -> MiningMode {
+        self.mode
+    }
+
+    /// Mines `blocks` blocks, or until stopped if `None`.
+    pub fn start(&mut self, blocks: Option<u64>) {
+        self.generated.clear();
+        self.done = None;
+        self.mode = match blocks {
+            Some(0) => MiningMode::Stopped,
+            Some(blocks) => MiningMode::Blocks(blocks),
+            None => MiningMode::Continuous,
+        };
+        self.abandon();
+    }
+
+    /// Mines `blocks` blocks and sends their heights and hashes once all of them are in the chain.
+    /// Dropped without an answer if mining is stopped or started again first.
+    pub fn generate(&mut self, blocks: u64) -> oneshot::Receiver<Vec<(usize, String)>> {
+        self.start(Some(blocks));
+        let (done, mined) = oneshot::channel();
+        match blocks {
+            0 => drop(done.send(vec![])),
+            _ => self.done = Some(done),
+        }
+        mined
+    }
+
+    pub fn stop(&mut self) {
+        self.generated.clear();
+        self.done = None;
+        self.mode = MiningMode::Stopped;
+        self.abandon();
+    }
+
+    fn abandon(&mut self) {
+        self.epoch.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gives the worker a job on top of `node`'s chain if it has none for the current state.
+    pub fn refresh(&mut self, node: &Node) {
+        if self.mode == MiningMode::Stopped {
+            return;
+        }
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        if self.job_epoch == Some(epoch) {
+            return;
+        }
+        self.job_epoch = Some(epoch);
+        match node.blockchain.mining_job(node.txs_for_next_block()) {
+            Ok(job) => {
+                debug!("mining {} transactions on {}", job.txs.len(), job.prev_hash);
+                let _ = self.jobs.send((epoch, job));
+            }
+            // Tried again once more transactions arrive
+            Err(e) => debug!("not mining yet: {}", e),
+        }
+    }
+
+    /// A block the worker found. It may have been mined on a tip we since moved away from.
+    pub fn poll_found(&mut self, cx: &mut Context) -> Poll<Block> {
+        match self.found.poll_recv(cx) {
+            Poll::Ready(Some(block)) => Poll::Ready(block),
+            _ => Poll::Pending,
+        }
+    }
+
+    /// Counts a found block which made it into the chain at `height`.
+    pub fn mined(&mut self, height: usize, hash: String) {
+        if self.done.is_some() {
+            self.generated.push((height, hash));
+        }
+        self.mode = match self.mode {
+            MiningMode::Blocks(1) => {
+                if let Some(done) = self.done.take() {
+                    let _ = done.send(std::mem::take(&mut self.generated));
+                }
+                MiningMode::Stopped
+            }
+            MiningMode::Blocks(blocks) => MiningMode::Blocks(blocks - 1),
+            mode => mode,
+        };
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        // The worker returns once it notices and finds the job channel closed
+        self.abandon();
+    }
+}
+
+fn work(jobs: std_mpsc::Receiver<(u64, MiningJob)>, found: mpsc::UnboundedSender<Block>, epoch: Arc<AtomicU64>) {
+    while let Ok((job_epoch, job)) = jobs.recv() {
+        if let Some(block) = job.mine(|| epoch.load(Ordering::Relaxed) != job_epoch) {
+            if found.send(block).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::miner::{Miner, MiningMode};
+    use crate::node::Node;
+    use crate::params::ChainParams;
+    use libp2p::futures::future::poll_fn;
+
+    #[tokio::test]
+    async fn test_miner() {
+        let mut node = Node::new(ChainParams::test().new_chain(256));
+        let mut miner = Miner::new(&mut node);
+        miner.refresh(&node);
+        assert_eq!(miner.job_epoch, None);
+
+        miner.start(Some(2));
+        for height in 1..=2 {
+            miner.refresh(&node);
+            let block = poll_fn(|cx| miner.poll_found(cx)).await;
+            let hash = block.hash.clone();
+            node.connect_block(block).unwrap();
+            miner.mined(height, hash);
+            assert_eq!(node.blockchain.len(), height + 1);
+        }
+        assert_eq!(miner.mode(), MiningMode::Stopped);
+
+        // Whoever asked for blocks hears about them once the last one is in
+        let mut mined = miner.generate(1);
+        miner.refresh(&node);
+        let block = poll_fn(|cx| miner.poll_found(cx)).await;
+        let hash = block.hash.clone();
+        node.connect_block(block).unwrap();
+        assert!(mined.try_recv().is_err());
+        miner.mined(3, hash.clone());
+        assert_eq!(mined.await, Ok(vec![(3, hash)]));
+        assert_eq!(miner.generate(0).await, Ok(vec![]));
+        let stopped = miner.generate(2);
+        miner.stop();
+        assert!(stopped.await.is_err());
+
+        // A new transaction restarts the job, so it makes it into the next block
+        miner.start(None);
+        miner.refresh(&node);
+        let tx = generate_blocks()[0].transactions[0].clone();
+        node.add_pending_tx(tx.clone());
+        miner.refresh(&node);
+        let block = loop {
+            let block = poll_fn(|cx| miner.poll_found(cx)).await;
+            if block.transactions.contains(&tx) {
+                break block;
+            }
+        };
+        node.connect_block(block).unwrap();
+        miner.stop();
+        assert_eq!(miner.mode(), MiningMode::Stopped);
+    }
+}
@@ -1,42 +1,233 @@
 use std::time::SystemTime;
 
-use crate::blockchain::Blockchain;
+use crate::{
+    block::{encoded_size, Block, MAX_BLOCK_SIZE},
+    blockchain::Blockchain,
+    error::ValidationError,
+    events::{Event, EventBus, EvictionReason},
+    params::ChainParams,
+    transaction::Transaction,
+};
+
+// Left for the block's own fields when filling it with transactions
+const BLOCK_HEADER_ALLOWANCE: usize = 1024;
+pub const DEFAULT_MAX_PENDING_TXS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// Ours has at least as much work.
+    KeptLocal,
+    /// The other chain had more work and replaced ours.
+    Switched { len: usize },
+    /// The other chain is broken or not of our network.
+    Rejected(ValidationError),
+    /// Our own chain was broken and got cut back to its last valid block,
+    /// the other chain did not beat what was left.
+    Repaired { len: usize },
+}
 
 pub struct Node {
+    pub params: ChainParams,
     pub blockchain: Blockchain,
+    pub pending_txs: Vec<Transaction>,
+    /// Transactions arriving once the pending pool is this full are dropped.
+    pub max_pending_txs: usize,
     pub last_time_synced: f64,
+    /// Where every change to the chain and the pending pool is published.
+    pub events: EventBus,
 }
 
 impl Node {
-    pub fn resolve_chain_conflict(&mut self, other: &Blockchain) {
-        let own_valid = self.blockchain.is_valid();
-        let other_valid = other.is_valid();
-        let correct_chain;
-        if own_valid && other_valid {
-            if self.blockchain.len() >= other.len() {
-                correct_chain = &self.blockchain;
-            } else {
-                correct_chain = &other;
+    pub fn new(blockchain: Blockchain) -> Self {
+        Node {
+            params: ChainParams::default(),
+            blockchain,
+            pending_txs: vec![],
+            max_pending_txs: DEFAULT_MAX_PENDING_TXS,
+            last_time_synced: 0.0,
+            events: EventBus::default(),
+        }
+    }
+
+    /// A node of the network described by `params`, starting from its genesis block.
+    pub fn from_params(params: ChainParams, concurrent_hashes: u64) -> Self {
+        let blockchain = params.new_chain(concurrent_hashes);
+        Node {
+            params,
+            ..Node::new(blockchain)
+        }
+    }
+
+    /// Whether the transaction is already pending or confirmed.
+    pub fn knows_transaction(&self, id: &str) -> bool {
+        let confirmed = self.blockchain.chain.iter().flat_map(|block| block.transactions.iter());
+        self.pending_txs.iter().chain(confirmed).any(|tx| tx.id() == id)
+    }
+
+    pub fn add_pending_tx(&mut self, tx: Transaction) {
+        if self.pending_txs.len() < self.max_pending_txs && !self.knows_transaction(&tx.id()) {
+            self.pending_txs.push(tx.clone());
+            self.events.publish(Event::TxAccepted(tx));
+        }
+    }
+
+    /// Appends `block` if it extends our tip, dropping its transactions from the pending pool.
+    /// The block's proof of work is expected to be checked already.
+    pub fn connect_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        let tip = self.blockchain.chain.last().ok_or(ValidationError::EmptyChain)?;
+        block.validate_parent(tip)?;
+        self.push_block(block);
+        Ok(())
+    }
+
+    fn push_block(&mut self, block: Block) {
+        self.remove_confirmed(&block);
+        self.blockchain.chain.push(block.clone());
+        self.events.publish(Event::BlockConnected {
+            height: self.blockchain.len() - 1,
+            block,
+        });
+    }
+
+    /// Pending transactions in arrival order, as many as fit into a block of `MAX_BLOCK_SIZE`.
+    pub fn txs_for_next_block(&self) -> Vec<Transaction> {
+        let mut size = BLOCK_HEADER_ALLOWANCE;
+        self.pending_txs
+            .iter()
+            .take_while(|tx| {
+                // Plus one for the separating comma
+                size += encoded_size(tx) + 1;
+                size <= MAX_BLOCK_SIZE
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Drops pending transactions which made it into `block`.
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        let confirmed: Vec<String> = block.transactions.iter().map(|tx| tx.id()).collect();
+        let (removed, kept): (Vec<_>, Vec<_>) = self.pending_txs.drain(..).partition(|tx| confirmed.contains(&tx.id()));
+        self.pending_txs = kept;
+        for tx in removed {
+            self.events.publish(Event::TxEvicted {
+                tx,
+                reason: EvictionReason::Confirmed,
+            });
+        }
+    }
+
+    /// Looks up transactions by id in the pending pool first, then in the chain.
+    pub fn find_transactions(&self, ids: &[String]) -> Vec<Transaction> {
+        let confirmed = self.blockchain.chain.iter().flat_map(|block| block.transactions.iter());
+        let mut found: Vec<Transaction> = vec![];
+        for tx in self.pending_txs.iter().chain(confirmed) {
+            if ids.contains(&tx.id()) && !found.contains(tx) {
+                found.push(tx.clone());
+            }
+        }
+        found
+    }
+
+    /// Cuts our chain back to its last valid block. Returns the new length if anything was cut.
+    pub fn repair_chain(&mut self) -> Option<usize> {
+        let valid = self.blockchain.valid_prefix_len();
+        if valid == self.blockchain.len() {
+            return None;
+        }
+        self.disconnect_from(valid);
+        Some(valid)
+    }
+
+    /// Blocks `other` shares with our chain, counted from genesis.
+    fn fork_point(&self, other: &Blockchain) -> usize {
+        self.blockchain
+            .chain
+            .iter()
+            .zip(&other.chain)
+            .take_while(|(ours, theirs)| ours.hash == theirs.hash)
+            .count()
+    }
+
+    // The work `other` claims is only worth comparing once every block we don't have
+    // passed the same checks as a block received on its own
+    fn check_candidate(&self, other: &Blockchain) -> Result<(), ValidationError> {
+        let genesis = other.chain.first().ok_or(ValidationError::EmptyChain)?;
+        match self.blockchain.chain.first() {
+            Some(own_genesis) if own_genesis.hash != genesis.hash => return Err(ValidationError::DifferentGenesis),
+            Some(_) => {}
+            None if !genesis.has_valid_hash() => return Err(ValidationError::InvalidHash),
+            None => {}
+        }
+        let difficulty = self.blockchain.difficulty();
+        for height in self.fork_point(other).max(1)..other.len() {
+            let block = &other.chain[height];
+            block.validate(difficulty)?;
+            block.validate_parent(&other.chain[height - 1])?;
+        }
+        Ok(())
+    }
+
+    /// Switches to `other` if it is valid and has more work than ours. Never fails on a bad
+    /// `other`, which may come straight from a peer.
+    pub fn resolve_chain_conflict(&mut self, other: &Blockchain) -> Resolution {
+        let repaired = self.repair_chain();
+        self.last_time_synced = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs_f64())
+            .unwrap_or_default();
+
+        if let Err(reason) = self.check_candidate(other) {
+            return match repaired {
+                Some(len) => Resolution::Repaired { len },
+                None => Resolution::Rejected(reason),
+            };
+        }
+        // Most cumulative work wins, ties keep what we already have
+        if self.blockchain.total_work() >= other.total_work() {
+            return match repaired {
+                Some(len) => Resolution::Repaired { len },
+                None => Resolution::KeptLocal,
+            };
+        }
+        let fork = self.fork_point(other);
+        let disconnected = self.disconnect_from(fork);
+        for block in other.chain[fork..].iter().cloned() {
+            self.push_block(block);
+        }
+        // Transactions of the blocks we left are pending again, unless the new chain has them too
+        for tx in disconnected.into_iter().rev().flat_map(|block| block.transactions) {
+            if tx.validate().is_ok() {
+                self.add_pending_tx(tx);
             }
-        } else if other_valid {
-            correct_chain = &other;
-        } else if own_valid {
-            correct_chain = &self.blockchain;
-        } else {
-            panic!("All chains are invalid");
         }
-        self.last_time_synced = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-		
+        Resolution::Switched { len: other.len() }
+    }
 
-		self.blockchain.chain = correct_chain.chain.clone();
+    /// Cuts our chain down to `len` blocks, tip first. Returns the blocks cut off, tip first.
+    fn disconnect_from(&mut self, len: usize) -> Vec<Block> {
+        let mut disconnected = vec![];
+        while self.blockchain.len() > len {
+            let block = self.blockchain.chain.pop().expect("chain is longer than len");
+            self.events.publish(Event::BlockDisconnected {
+                height: self.blockchain.len(),
+                block: block.clone(),
+            });
+            disconnected.push(block);
+        }
+        disconnected
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::block::tests::generate_blocks;
-    use crate::blockchain::tests::generate_blockchain;
-	use crate::node::Node;
+    use crate::block::{encoded_size, tests::generate_blocks, Block, MAX_BLOCK_SIZE};
+    use crate::blockchain::{tests::generate_blockchain, Blockchain};
+    use crate::error::ValidationError;
+    use crate::events::{Event, EvictionReason};
+	use crate::node::{Node, Resolution};
+    use crate::params::ChainParams;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
 
     #[test]
     fn test_conflict(){
@@ -44,20 +235,145 @@ mod tests {
 
         assert!(chain.is_valid());
 
-        let mut invalid_chain = generate_blockchain();
+        let mut invalid_chain = chain.clone();
 
         invalid_chain.chain.append(&mut generate_blocks());
 
         assert!(!invalid_chain.is_valid());
 
-        let mut node = Node{
-            blockchain: invalid_chain,
-            last_time_synced: 0.0,
-        };
+        let mut node = Node::new(invalid_chain.clone());
+
+        // Our broken chain is cut back to its valid part, which the other chain does not beat
+        assert_eq!(node.resolve_chain_conflict(&chain), Resolution::Repaired { len: chain.len() });
+
+        assert!(node.blockchain == chain);
 
-        node.resolve_chain_conflict(&chain);
+        assert_eq!(node.resolve_chain_conflict(&chain), Resolution::KeptLocal);
+        assert_eq!(
+            node.resolve_chain_conflict(&invalid_chain),
+            Resolution::Rejected(ValidationError::InvalidHash)
+        );
+        assert_eq!(
+            node.resolve_chain_conflict(&Blockchain::new(1, 1, 256)),
+            Resolution::Rejected(ValidationError::EmptyChain)
+        );
+        let other_network = ChainParams::test().new_chain(256);
+        assert_eq!(
+            node.resolve_chain_conflict(&other_network),
+            Resolution::Rejected(ValidationError::DifferentGenesis)
+        );
 
+        // Neither a block without the work nor one claiming work its hash doesn't have beats ours
+        let mut longer = chain.clone();
+        let mut next = Block::new(chain.chain.last().unwrap().hash.clone(), vec![], 0, SystemTime::now());
+        next.generate_hash();
+        longer.chain.push(next);
+        assert_eq!(
+            node.resolve_chain_conflict(&longer),
+            Resolution::Rejected(ValidationError::InsufficientWork { difficulty: 5 })
+        );
+        longer.chain.last_mut().unwrap().hash = "0".repeat(64);
+        assert_eq!(node.resolve_chain_conflict(&longer), Resolution::Rejected(ValidationError::InvalidHash));
         assert!(node.blockchain == chain);
 
+        // A mined chain with more work is adopted, and the pending pool follows it
+        let mut node = Node::from_params(ChainParams::test(), 256);
+        let ours = generate_blocks()[0].transactions[0].clone();
+        let mut theirs = ours.clone();
+        theirs.amount += 1;
+        let mut other = node.blockchain.clone();
+        node.add_pending_tx(ours.clone());
+        node.add_pending_tx(theirs.clone());
+        let mut mined = node.blockchain.clone();
+        mined.try_mine(vec![ours.clone()]).unwrap();
+        node.connect_block(mined.chain[1].clone()).unwrap();
+
+        other.try_mine(vec![theirs]).unwrap();
+        while other.total_work() <= node.blockchain.total_work() {
+            other.try_mine(vec![]).unwrap();
+        }
+        assert_eq!(node.resolve_chain_conflict(&other), Resolution::Switched { len: other.len() });
+        assert!(node.blockchain == other);
+        assert_eq!(node.pending_txs, vec![ours]);
+    }
+
+    #[test]
+    fn test_connect_block() {
+        let mut node = Node::from_params(ChainParams::test(), 256);
+        let tx = generate_blocks()[0].transactions[0].clone();
+        node.add_pending_tx(tx.clone());
+
+        let mut miner = node.blockchain.clone();
+        miner.try_mine(vec![tx]).unwrap();
+        let block = miner.chain.last().unwrap().clone();
+
+        assert_eq!(node.connect_block(block.clone()), Ok(()));
+        assert_eq!(node.blockchain, miner);
+        assert!(node.pending_txs.is_empty());
+        // Does not extend the tip anymore
+        assert!(matches!(node.connect_block(block), Err(ValidationError::UnknownParent(_))));
+
+        node.max_pending_txs = 1;
+        let mut other = generate_blocks()[0].transactions[0].clone();
+        node.add_pending_tx(other.clone());
+        other.amount += 1;
+        node.add_pending_tx(other);
+        assert_eq!(node.pending_txs.len(), 1);
+    }
+
+    #[test]
+    fn test_txs_for_next_block() {
+        let mut node = Node::from_params(ChainParams::test(), 256);
+        let mut tx = generate_blocks()[0].transactions[0].clone();
+        tx.from = "a".repeat(1000);
+        node.pending_txs = vec![tx; 2000];
+
+        let txs = node.txs_for_next_block();
+        assert!(!txs.is_empty() && txs.len() < node.pending_txs.len());
+        assert!(encoded_size(&txs) < MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut node = Node::from_params(ChainParams::test(), 256);
+        let events = Arc::new(Mutex::new(vec![]));
+        let published = events.clone();
+        node.events.subscribe(move |event| published.lock().unwrap().push(event.clone()));
+        let tx = generate_blocks()[0].transactions[0].clone();
+        node.add_pending_tx(tx.clone());
+
+        let fork = node.blockchain.clone();
+        let mut miner = fork.clone();
+        miner.try_mine(vec![tx.clone()]).unwrap();
+        let mined = miner.chain[1].clone();
+        node.connect_block(mined.clone()).unwrap();
+
+        // Work is counted from the hashes, so two blocks don't always beat one
+        let mut longer = fork;
+        while longer.len() < 3 || longer.total_work() <= node.blockchain.total_work() {
+            longer.try_mine(vec![]).unwrap();
+        }
+        assert_eq!(node.resolve_chain_conflict(&longer), Resolution::Switched { len: longer.len() });
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[..4],
+            [
+                Event::TxAccepted(tx.clone()),
+                Event::TxEvicted { tx: tx.clone(), reason: EvictionReason::Confirmed },
+                Event::BlockConnected { height: 1, block: mined.clone() },
+                Event::BlockDisconnected { height: 1, block: mined },
+            ]
+        );
+        // The disconnected block's transaction is pending again once the new blocks are in
+        assert_eq!(events.last(), Some(&Event::TxAccepted(tx)));
+        let connected: Vec<_> = events[4..events.len() - 1]
+            .iter()
+            .map(|event| match event {
+                Event::BlockConnected { height, .. } => *height,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(connected, (1..longer.len()).collect::<Vec<_>>());
     }
 }
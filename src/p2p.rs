@@ -1,97 +1,807 @@
 use libp2p::{
-    floodsub,
-    floodsub::{Floodsub, FloodsubEvent},
+    gossipsub::{
+        Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAcceptance, MessageAuthenticity,
+        MessageId,
+    },
+    identify::{Identify, IdentifyEvent},
+    identity::Keypair,
+    mplex,
+    noise::{self, NoiseConfig, X25519Spec},
+    tcp::TokioTcpConfig,
+    kad::{store::MemoryStore, Kademlia, KademliaEvent},
     mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, Swarm},
-    NetworkBehaviour, PeerId,
+    request_response::{OutboundFailure, RequestResponse, RequestResponseEvent, RequestResponseMessage},
+    swarm::{
+        toggle::Toggle, CloseConnection, DialPeerCondition, IntoProtocolsHandler, NetworkBehaviour,
+        NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters, ProtocolsHandler, Swarm, SwarmBuilder,
+        SwarmEvent,
+    },
+    core::{upgrade, ConnectedPoint},
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
 };
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use tokio::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::task::{Context, Poll};
+use libp2p::futures::StreamExt;
+use log::{debug, info, warn};
+use std::time::{Duration, Instant};
+use tokio::{select, spawn, sync::oneshot, time::sleep};
 
-use crate::{block::Block, blockchain::Blockchain, node::Node};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ChainResponse {
-    pub blockchain: Blockchain,
-    pub receiver: String,
-}
+use crate::{
+    address_book::{self, AddressBook, RECONNECT_PEERS},
+    discovery::{self, NetworkConfig},
+    gossip,
+    block::Block,
+    compact_block::{CompactBlock, PartialBlock, PartialBlocks},
+    dandelion::{Dandelion, Route},
+    error::{NodeError, ValidationError},
+    events::Event,
+    keyfile,
+    handshake::{new_handshake, Handshake, HandshakeCodec},
+    message::{MessageError, NetworkMessage},
+    metrics::Metrics,
+    miner::Miner,
+    node::{Node, Resolution},
+    peer_manager::{Misbehavior, PeerManager},
+    transaction::Transaction,
+    protocol::{blocks_within_limit, new_chain_exchange, ChainExchangeCodec, ChainRequest, ChainResponse},
+    sync::{HeaderSync, SyncError, TipAnnouncement, MAX_HEADERS_PER_MESSAGE},
+};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LocalChainRequest {
-    pub from_peer_id: String,
-}
+type HandlerInEvent =
+    <<<AppBehaviour as NetworkBehaviour>::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent;
 
 pub enum EventType {
-    LocalChainResponse(ChainResponse),
-    Init,
+    Sync,
     Cli,
 }
 
 #[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll")]
 pub struct AppBehaviour {
-    pub floodsub: Floodsub,
-    pub mdns: Mdns,
-    #[behaviour(ignore)]
-    pub response_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub gossipsub: Gossipsub,
+    pub mdns: Toggle<Mdns>,
+    pub kademlia: Kademlia<MemoryStore>,
+    pub identify: Identify,
+    pub handshake: RequestResponse<HandshakeCodec>,
+    pub chain_exchange: RequestResponse<ChainExchangeCodec>,
     #[behaviour(ignore)]
     pub node: Node,
     #[behaviour(ignore)]
+    pub sync: HeaderSync,
+    #[behaviour(ignore)]
     pub peer_id: PeerId,
     #[behaviour(ignore)]
-    pub blockchain_topic: floodsub::Topic,
+    pub blockchain_topic: IdentTopic,
+    #[behaviour(ignore)]
+    pub transaction_topic: IdentTopic,
+    // Peers found by discovery, dialed from `poll` once their addresses are known
+    #[behaviour(ignore)]
+    dial_queue: Vec<PeerId>,
+    #[behaviour(ignore)]
+    pub address_book: AddressBook,
+    #[behaviour(ignore)]
+    pub peer_manager: PeerManager,
+    // Peers to hang up on, disconnected from `poll`
+    #[behaviour(ignore)]
+    disconnect_queue: Vec<PeerId>,
+    // Peers whose handshake we accepted. Only they are synced with.
+    #[behaviour(ignore)]
+    peers: HashMap<PeerId, Handshake>,
+    // Peers of another network or version, not dialed again
+    #[behaviour(ignore)]
+    incompatible: HashSet<PeerId>,
+    // Compact blocks waiting for transactions we asked their sender for
+    #[behaviour(ignore)]
+    partial_blocks: PartialBlocks,
+    // Stem relay state when Dandelion is enabled
+    #[behaviour(ignore)]
+    dandelion: Option<Dandelion>,
+    #[behaviour(ignore)]
+    pub metrics: Metrics,
     #[behaviour(ignore)]
-    pub transaction_topic: floodsub::Topic,
+    pub miner: Miner,
 }
 
 impl AppBehaviour {
-    pub async fn new(
-        peer_id: PeerId,
-        node: Node,
-        response_sender: mpsc::UnboundedSender<ChainResponse>,
-    ) -> Self {
+    pub async fn new(keys: Keypair, mut node: Node, config: &NetworkConfig) -> Self {
+        let mut gossipsub = Gossipsub::new(MessageAuthenticity::Signed(keys.clone()), gossip::gossipsub_config())
+            .expect("can create gossipsub");
+        let (score_params, score_thresholds) = gossip::peer_score_params();
+        gossipsub
+            .with_peer_score(score_params, score_thresholds)
+            .expect("valid peer score params");
+
+        let address_book = match &config.address_book {
+            Some(path) => AddressBook::load(path).unwrap_or_else(|e| {
+                // Keep the broken file around instead of overwriting it
+                warn!("could not read address book {}: {}, not persisting peers", path.display(), e);
+                AddressBook::in_memory()
+            }),
+            None => AddressBook::in_memory(),
+        };
+
+        let metrics = Metrics::new(&mut node);
+        let miner = Miner::new(&mut node);
         let mut behaviour = Self {
-            node: node,
-            peer_id: peer_id,
-            floodsub: Floodsub::new(peer_id),
-            mdns: Mdns::new(Default::default())
-                .await
-                .expect("can create mdns"),
-
-            blockchain_topic: floodsub::Topic::new("blockchain"),
-            transaction_topic: floodsub::Topic::new("transactions"),
-            response_sender,
+            node,
+            metrics,
+            miner,
+            peer_id: PeerId::from(keys.public()),
+            gossipsub,
+            mdns: Toggle::from(match config.mdns {
+                true => Some(Mdns::new(Default::default()).await.expect("can create mdns")),
+                false => None,
+            }),
+            kademlia: discovery::new_kademlia(PeerId::from(keys.public())),
+            identify: discovery::new_identify(keys.public()),
+            handshake: new_handshake(),
+            chain_exchange: new_chain_exchange(),
+
+            blockchain_topic: gossip::blocks_topic(),
+            transaction_topic: gossip::transactions_topic(),
+            sync: HeaderSync::default(),
+            dial_queue: vec![],
+            address_book,
+            peer_manager: PeerManager::default(),
+            disconnect_queue: vec![],
+            peers: HashMap::new(),
+            incompatible: HashSet::new(),
+            partial_blocks: PartialBlocks::default(),
+            dandelion: if config.dandelion { Some(Dandelion::default()) } else { None },
         };
-        
-        behaviour
-            .floodsub
-            .subscribe(behaviour.blockchain_topic.clone());
-        
+
+        for topic in [&behaviour.blockchain_topic, &behaviour.transaction_topic] {
+            behaviour
+                .gossipsub
+                .subscribe(topic)
+                .expect("can subscribe to topic");
+        }
+
         behaviour
     }
+
+    fn publish(&mut self, topic: IdentTopic, message: NetworkMessage) {
+        self.metrics.sent(message.kind());
+        // Having nobody to publish to yet is fine, peers catch up through sync
+        if let Err(e) = self.gossipsub.publish(topic, message.encode()) {
+            debug!("could not publish message: {:?}", e);
+        }
+    }
+
+    /// Peers which completed the handshake.
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn peer_handshake(&self, peer: &PeerId) -> Option<&Handshake> {
+        self.peers.get(peer)
+    }
+
+    pub fn is_incompatible(&self, peer: &PeerId) -> bool {
+        self.incompatible.contains(peer)
+    }
+
+    pub fn start_handshake(&mut self, peer: PeerId) {
+        let local = Handshake::from_node(&self.node);
+        self.metrics.sent("Handshake");
+        self.handshake.send_request(&peer, local);
+    }
+
+    /// Accepts the peer if its handshake is compatible, otherwise marks it as incompatible.
+    fn on_handshake(&mut self, peer: PeerId, remote: Handshake) -> bool {
+        let local = Handshake::from_node(&self.node);
+        match remote.check_compatible(&local) {
+            Ok(()) => {
+                let best_height = remote.best_height;
+                let is_new = self.peers.insert(peer, remote).is_none();
+                if is_new {
+                    self.node.events.publish(Event::PeerConnected {
+                        peer: peer.to_string(),
+                        best_height,
+                    });
+                    // Let the new peer know about our tip and catch up with theirs
+                    self.announce_tip();
+                    self.start_sync();
+                }
+                true
+            }
+            Err(e) => {
+                info!("disconnecting {}: {}", peer, e);
+                self.mark_incompatible(peer);
+                false
+            }
+        }
+    }
+
+    // Called by the derived `NetworkBehaviour::poll` after all sub-behaviours
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerInEvent, ()>> {
+        while let Poll::Ready(block) = self.miner.poll_found(cx) {
+            self.connect_mined(block);
+            // Gossipsub has the block to send now, so it needs polling again
+            cx.waker().wake_by_ref();
+        }
+        self.miner.refresh(&self.node);
+        if let Some(peer_id) = self.disconnect_queue.pop() {
+            return Poll::Ready(NetworkBehaviourAction::CloseConnection {
+                peer_id,
+                connection: CloseConnection::All,
+            });
+        }
+        while let Some(peer_id) = self.dial_queue.pop() {
+            if self.peer_manager.is_banned(&peer_id, Instant::now()) {
+                continue;
+            }
+            return Poll::Ready(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition: DialPeerCondition::Disconnected,
+            });
+        }
+        Poll::Pending
+    }
+
+    /// Raises the misbehavior score of `peer`, and bans it once the score gets too high.
+    pub fn penalize(&mut self, peer: PeerId, offense: Misbehavior) {
+        if offense != Misbehavior::TooManyRequests {
+            self.metrics.invalid(&offense.to_string());
+        }
+        if self.peer_manager.misbehaved(&peer, offense, Instant::now()) {
+            warn!("banning {} after {}", peer, offense);
+            self.peers.remove(&peer);
+            self.kademlia.remove_peer(&peer);
+            self.disconnect_queue.push(peer);
+        }
+    }
+
+    fn mark_incompatible(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.incompatible.insert(peer);
+        self.kademlia.remove_peer(&peer);
+    }
+
+    /// Remembers where `peer` can be reached, for Kademlia and for chain requests.
+    pub fn add_peer_address(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.kademlia.add_address(&peer, addr.clone());
+        self.chain_exchange.add_address(&peer, addr);
+    }
+
+    /// Forgets long unseen peers and writes the address book to disk.
+    pub fn save_address_book(&mut self) {
+        self.address_book.prune(address_book::unix_now());
+        if let Err(e) = self.address_book.save() {
+            warn!("could not save address book: {}", e);
+        }
+    }
+
+    /// Looks up peers close to us, and a random id to explore other parts of the network.
+    pub fn bootstrap_discovery(&mut self) {
+        if self.kademlia.bootstrap().is_ok() {
+            self.kademlia.get_closest_peers(PeerId::random());
+        }
+    }
+
+    /// Starts a header-first sync round, or retries timed out requests of the current one.
+    pub fn start_sync(&mut self) {
+        let peers = self.connected_peers();
+        let requests = self.sync.start(&self.node.blockchain, &peers, Instant::now());
+        self.send_requests(requests);
+    }
+
+    /// Tells peers about our tip, they request whatever they are missing.
+    pub fn announce_tip(&mut self) {
+        if let Some(tip) = TipAnnouncement::from_chain(&self.node.blockchain) {
+            self.publish(self.blockchain_topic.clone(), NetworkMessage::TipAnnouncement(tip));
+        }
+    }
+
+    /// Pushes our newly mined tip block as a compact block, so peers don't have to fetch it
+    /// and only ask for the transactions they haven't seen.
+    pub fn publish_tip_block(&mut self) {
+        if let Some(block) = self.node.blockchain.chain.last() {
+            let compact = CompactBlock::from_block(block, self.node.blockchain.len() - 1);
+            self.publish(self.blockchain_topic.clone(), NetworkMessage::CompactBlock(compact));
+        }
+    }
+
+    /// Has the background miner mine `blocks` blocks, see `Miner::generate`. Fails right away
+    /// if there is nothing to mine, instead of waiting for transactions that may never come.
+    pub fn generate(&mut self, blocks: u64) -> Result<oneshot::Receiver<Vec<(usize, String)>>, ValidationError> {
+        self.node.blockchain.mining_job(self.node.txs_for_next_block())?;
+        Ok(self.miner.generate(blocks))
+    }
+
+    /// Mines the pending transactions that fit into a block right away and pushes the block to peers.
+    /// Returns its hash. Whatever did not fit waits for the next block.
+    #[cfg(test)]
+    pub fn mine_block(&mut self) -> Result<String, ValidationError> {
+        let pending_txs = self.node.txs_for_next_block();
+        let hash = self.node.blockchain.try_mine(pending_txs)?;
+        // Connected again like any other block, so it gets published
+        let mined = self.node.blockchain.chain.pop().expect("just mined a block");
+        self.node.connect_block(mined)?;
+        self.publish_tip_block();
+        Ok(hash)
+    }
+
+    fn connect_mined(&mut self, block: Block) {
+        let hash = block.hash.clone();
+        match self.node.connect_block(block) {
+            Ok(()) => {
+                let height = self.node.blockchain.len() - 1;
+                info!("mined block {} at height {}", hash, height);
+                self.miner.mined(height, hash);
+                self.publish_tip_block();
+            }
+            // Mined on a tip that a peer's block replaced meanwhile
+            Err(e) => debug!("dropping mined block {}: {}", hash, e),
+        }
+    }
+
+    /// Sends out a transaction we created, along a stem if Dandelion is enabled.
+    pub fn submit_transaction(&mut self, tx: Transaction) {
+        if self.dandelion.is_some() {
+            self.stem_transaction(None, tx);
+        } else {
+            self.fluff_transaction(tx);
+        }
+    }
+
+    /// Broadcasts `tx` and adds it to our pending pool.
+    fn fluff_transaction(&mut self, tx: Transaction) {
+        if let Some(dandelion) = self.dandelion.as_mut() {
+            dandelion.fluffed(&tx.id());
+        }
+        // Possibly broadcast by someone else or even mined in the meantime
+        if self.node.knows_transaction(&tx.id()) {
+            return;
+        }
+        self.publish(self.transaction_topic.clone(), NetworkMessage::Transaction(tx.clone()));
+        self.node.add_pending_tx(tx);
+    }
+
+    /// Forwards `tx` from `source` to a single peer, or broadcasts it if this is where the stem ends.
+    /// The transaction stays out of our pending pool until it is broadcast, so it can't be
+    /// asked for to find out where it came from.
+    fn stem_transaction(&mut self, source: Option<PeerId>, tx: Transaction) {
+        let peers = self.connected_peers();
+        let dandelion = match self.dandelion.as_mut() {
+            Some(dandelion) => dandelion,
+            None => return self.fluff_transaction(tx),
+        };
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        if !dandelion.embargo(tx.clone(), now, &mut rng) {
+            if !dandelion.is_embargoed(&tx.id()) {
+                // No room to keep it until the stem is done
+                self.fluff_transaction(tx);
+            }
+            return;
+        }
+        match dandelion.route(source, &peers, now, &mut rng) {
+            Route::Stem(peer) => self.send_requests(vec![(peer, ChainRequest::StemTransaction { tx })]),
+            Route::Fluff => self.fluff_transaction(tx),
+        }
+    }
+
+    fn handle_stem_transaction(&mut self, source: PeerId, tx: Transaction) {
+        match gossip::validate_transaction(&self.node, &tx) {
+            MessageAcceptance::Accept => self.stem_transaction(Some(source), tx),
+            MessageAcceptance::Ignore => {}
+            MessageAcceptance::Reject => self.penalize(source, Misbehavior::InvalidTransaction),
+        }
+    }
+
+    /// Broadcasts stem transactions we haven't seen broadcast in time, so none get lost
+    /// on a stem whose next node went away.
+    pub fn fluff_expired(&mut self) {
+        let expired = match self.dandelion.as_mut() {
+            Some(dandelion) => dandelion.take_expired(Instant::now()),
+            None => return,
+        };
+        for tx in expired {
+            self.fluff_transaction(tx);
+        }
+    }
+
+    fn handle_gossip(&mut self, source: PeerId, message_id: MessageId, message: GossipsubMessage) {
+        let (acceptance, offense) = if !self.peers.contains_key(&source) {
+            (MessageAcceptance::Ignore, Misbehavior::InvalidMessage)
+        } else {
+            let on_blocks_topic = message.topic == self.blockchain_topic.hash();
+            let on_transactions_topic = message.topic == self.transaction_topic.hash();
+            let decoded = NetworkMessage::decode(&message.data);
+            self.metrics
+                .received(decoded.as_ref().map_or("Undecodable", |message| message.kind()));
+            match decoded {
+                Ok(NetworkMessage::TipAnnouncement(tip)) if on_blocks_topic => {
+                    (self.handle_tip(source, tip), Misbehavior::InvalidBlock)
+                }
+                Ok(NetworkMessage::Block { height, block }) if on_blocks_topic => {
+                    (self.handle_block(source, height, block), Misbehavior::InvalidBlock)
+                }
+                Ok(NetworkMessage::CompactBlock(compact)) if on_blocks_topic => {
+                    (self.handle_compact_block(source, compact), Misbehavior::InvalidBlock)
+                }
+                Ok(NetworkMessage::Transaction(tx)) if on_transactions_topic => {
+                    if let Some(dandelion) = self.dandelion.as_mut() {
+                        dandelion.fluffed(&tx.id());
+                    }
+                    let acceptance = gossip::validate_transaction(&self.node, &tx);
+                    if let MessageAcceptance::Accept = acceptance {
+                        self.node.add_pending_tx(tx);
+                    }
+                    (acceptance, Misbehavior::InvalidTransaction)
+                }
+                Ok(other) => {
+                    debug!("unexpected {} message from {} on {}", other.kind(), source, message.topic);
+                    (MessageAcceptance::Reject, Misbehavior::InvalidMessage)
+                }
+                // Possibly a newer node, don't punish it but don't forward what we don't understand either
+                Err(e @ MessageError::UnknownKind(_)) | Err(e @ MessageError::UnsupportedVersion(_)) => {
+                    debug!("ignoring message from {}: {}", source, e);
+                    (MessageAcceptance::Ignore, Misbehavior::InvalidMessage)
+                }
+                Err(e @ MessageError::TooLarge { .. }) => {
+                    info!("oversized message from {}: {}", source, e);
+                    (MessageAcceptance::Reject, Misbehavior::OversizedMessage)
+                }
+                Err(e) => {
+                    info!("invalid message from {}: {}", source, e);
+                    (MessageAcceptance::Reject, Misbehavior::InvalidMessage)
+                }
+            }
+        };
+
+        if let MessageAcceptance::Reject = acceptance {
+            info!("rejected invalid message from {}", source);
+            self.penalize(source, offense);
+        }
+        // Rejected messages are not forwarded and count against the sender's score
+        if let Err(e) = self
+            .gossipsub
+            .report_message_validation_result(&message_id, &source, acceptance)
+        {
+            debug!("could not report validation result: {:?}", e);
+        }
+    }
+
+    fn handle_tip(&mut self, source: PeerId, tip: TipAnnouncement) -> MessageAcceptance {
+        let acceptance = gossip::validate_tip(&self.node, &tip);
+        if let MessageAcceptance::Accept = acceptance {
+            if let Some(handshake) = self.peers.get_mut(&source) {
+                handshake.best_height = handshake.best_height.max(tip.height);
+            }
+            let requests = self
+                .sync
+                .on_announcement(source, &tip, &self.node.blockchain, Instant::now());
+            self.send_requests(requests);
+        }
+        acceptance
+    }
+
+    fn handle_block(&mut self, source: PeerId, height: usize, block: Block) -> MessageAcceptance {
+        let acceptance = gossip::validate_block(&self.node, &block);
+        if let MessageAcceptance::Accept = acceptance {
+            // A block on top of a chain we don't have is fetched through sync like any other tip
+            if let Err(ValidationError::UnknownParent(_)) = self.node.connect_block(block.clone()) {
+                return self.handle_tip(source, TipAnnouncement { header: block.header(), height });
+            }
+            if let Some(handshake) = self.peers.get_mut(&source) {
+                handshake.best_height = handshake.best_height.max(height);
+            }
+        }
+        acceptance
+    }
+
+    fn handle_compact_block(&mut self, source: PeerId, compact: CompactBlock) -> MessageAcceptance {
+        let tip = TipAnnouncement {
+            header: compact.header.clone(),
+            height: compact.height,
+        };
+        let acceptance = gossip::validate_tip(&self.node, &tip);
+        if !matches!(acceptance, MessageAcceptance::Accept) || self.partial_blocks.contains(&tip.header.hash) {
+            return acceptance;
+        }
+        // Only a block on top of our tip can be rebuilt from the pool, anything further is synced
+        if self.node.blockchain.chain.last().map(|block| &block.hash) != Some(&tip.header.prev_hash) {
+            return self.handle_tip(source, tip);
+        }
+
+        let partial = compact.fill_from(&self.node.pending_txs);
+        let missing = partial.missing();
+        if missing.is_empty() {
+            self.complete_block(source, partial);
+            return acceptance;
+        }
+        self.partial_blocks.insert(partial);
+        let request = ChainRequest::GetBlockTransactions {
+            block_hash: tip.header.hash,
+            indexes: missing,
+        };
+        self.send_requests(vec![(source, request)]);
+        // Not forwarded until we have rebuilt it, or our peers would ask us for transactions we lack
+        MessageAcceptance::Ignore
+    }
+
+    fn complete_block(&mut self, source: PeerId, partial: PartialBlock) {
+        let block = match partial.to_block() {
+            Some(block) => block,
+            None => return,
+        };
+        match block.validate(self.node.blockchain.difficulty()) {
+            Ok(()) => {
+                self.handle_block(source, partial.compact.height, block);
+            }
+            // The header was valid, so short ids collided with other transactions of our pool.
+            // Fetch the block through sync instead.
+            Err(ValidationError::InvalidHash) => {
+                let tip = TipAnnouncement {
+                    header: partial.compact.header,
+                    height: partial.compact.height,
+                };
+                self.handle_tip(source, tip);
+            }
+            Err(e) => {
+                info!("invalid block {} from {}: {}", block.hash, source, e);
+                self.penalize(source, Misbehavior::InvalidBlock);
+            }
+        }
+    }
+
+    pub fn request_transactions(&mut self, peer: PeerId, ids: Vec<String>) {
+        self.send_requests(vec![(peer, ChainRequest::GetTransactions { ids })]);
+    }
+
+    fn send_requests(&mut self, requests: Vec<(PeerId, ChainRequest)>) {
+        for (peer, request) in requests {
+            self.metrics.sent(request.kind());
+            self.chain_exchange.send_request(&peer, request);
+        }
+    }
+
+    fn handle_request(&mut self, peer: PeerId, request: ChainRequest) -> ChainResponse {
+        match request {
+            ChainRequest::GetHeaders { locator } => ChainResponse::Headers(
+                self.node
+                    .blockchain
+                    .headers_after(&locator, MAX_HEADERS_PER_MESSAGE),
+            ),
+            ChainRequest::GetBlocks { hashes } => {
+                ChainResponse::Blocks(blocks_within_limit(self.node.blockchain.blocks_by_hash(&hashes)))
+            }
+            ChainRequest::GetTransactions { ids } => {
+                ChainResponse::Transactions(self.node.find_transactions(&ids))
+            }
+            ChainRequest::GetBlockTransactions { block_hash, indexes } => {
+                let chain = &self.node.blockchain;
+                let txs = chain
+                    .height_of(&block_hash)
+                    .map(|height| {
+                        let block = &chain.chain[height];
+                        indexes
+                            .iter()
+                            .filter_map(|index| block.transactions.get(*index).cloned())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                ChainResponse::BlockTransactions { block_hash, txs }
+            }
+            ChainRequest::StemTransaction { tx } => {
+                self.handle_stem_transaction(peer, tx);
+                ChainResponse::Ack
+            }
+        }
+    }
+
+    fn publish_sync_progress(&mut self, completed: Option<usize>) {
+        let progress = completed.map(|total| (total, total)).or_else(|| self.sync.progress());
+        if let Some((downloaded, total)) = progress {
+            self.node.events.publish(Event::SyncProgress { downloaded, total });
+        }
+    }
+
+    fn handle_response(&mut self, peer: PeerId, response: ChainResponse) {
+        let peers = self.connected_peers();
+        let requests = match response {
+            ChainResponse::Headers(headers) => {
+                match self
+                    .sync
+                    .on_headers(peer, headers, &self.node.blockchain, &peers, Instant::now())
+                {
+                    Ok(requests) => {
+                        self.publish_sync_progress(None);
+                        requests
+                    }
+                    // Peer is simply behind or on a weaker fork
+                    Err(SyncError::NotEnoughWork { .. }) => vec![],
+                    Err(e) => {
+                        info!("rejected headers from {}: {}", peer, e);
+                        let offense = match e {
+                            SyncError::TooManyHeaders(_) => Misbehavior::OversizedMessage,
+                            _ => Misbehavior::InvalidHeaders,
+                        };
+                        self.penalize(peer, offense);
+                        vec![]
+                    }
+                }
+            }
+            ChainResponse::Blocks(blocks) => {
+                let total = self.sync.progress().map(|(_, total)| total);
+                let (requests, candidate) = self.sync.on_blocks(
+                    peer,
+                    blocks,
+                    &self.node.blockchain,
+                    &peers,
+                    Instant::now(),
+                );
+                // All of them, when the download completed
+                self.publish_sync_progress(total.filter(|_| candidate.is_some()));
+                if let Some(candidate) = candidate {
+                    match self.node.resolve_chain_conflict(&candidate) {
+                        Resolution::Switched { len } => info!("switched to chain of {} blocks from {}", len, peer),
+                        Resolution::Repaired { len } => warn!("cut broken local chain back to {} blocks", len),
+                        // The candidate was built from headers we checked, so it has to be the peer's blocks
+                        Resolution::Rejected(reason) => {
+                            info!("rejected chain from {}: {}", peer, reason);
+                            self.penalize(peer, Misbehavior::InvalidBlock);
+                        }
+                        Resolution::KeptLocal => {}
+                    }
+                }
+                requests
+            }
+            ChainResponse::Transactions(txs) => {
+                for tx in txs {
+                    self.node.add_pending_tx(tx);
+                }
+                vec![]
+            }
+            ChainResponse::Ack => vec![],
+            ChainResponse::BlockTransactions { block_hash, txs } => {
+                // Not waiting for it anymore if the block was dropped or arrived otherwise
+                if let Some(mut partial) = self.partial_blocks.remove(&block_hash) {
+                    if txs.is_empty() {
+                        // The peer doesn't have the block in its chain (anymore), so sync it instead
+                        let tip = TipAnnouncement {
+                            header: partial.compact.header,
+                            height: partial.compact.height,
+                        };
+                        self.handle_tip(peer, tip);
+                    } else if partial.fill_missing(txs) {
+                        self.complete_block(peer, partial);
+                    } else {
+                        info!("{} sent transactions not matching block {}", peer, block_hash);
+                        self.penalize(peer, Misbehavior::InvalidMessage);
+                    }
+                }
+                vec![]
+            }
+        };
+
+        self.send_requests(requests);
+    }
 }
 
 // incoming event handler
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == self.peer_id.to_string() {
-
-                    self.node.resolve_chain_conflict(&resp.blockchain);
-                }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                let peer_id = resp.from_peer_id;
-                if self.peer_id.to_string() == peer_id {
-                    if let Err(e) = self.response_sender.send(ChainResponse {
-                        blockchain: self.node.blockchain.clone(),
-                        receiver: msg.source.to_string(),
-                    }) {
-                        println!("error sending response via channel, {} \r\n", e);
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        {
+            self.handle_gossip(propagation_source, message_id, message);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<ChainRequest, ChainResponse>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<ChainRequest, ChainResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                // Chain data is only served after the handshake, dropping the channel fails the request
+                RequestResponseMessage::Request { .. } if !self.peers.contains_key(&peer) => {}
+                RequestResponseMessage::Request { .. } if !self.peer_manager.allow_request(&peer, Instant::now()) => {
+                    self.penalize(peer, Misbehavior::TooManyRequests);
+                }
+                RequestResponseMessage::Request { request, channel, .. } => {
+                    self.metrics.received(request.kind());
+                    let response = self.handle_request(peer, request);
+                    self.metrics.sent(response.kind());
+                    if self.chain_exchange.send_response(channel, response).is_err() {
+                        debug!("peer {} went away before we could respond", peer);
+                    }
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    self.metrics.received(response.kind());
+                    self.handle_response(peer, response);
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!("request to {} failed: {}", peer, error);
+                let peers = self.connected_peers();
+                let retries =
+                    self.sync
+                        .on_request_failed(peer, &self.node.blockchain, &peers, Instant::now());
+                self.send_requests(retries);
+            }
+            // Includes requests we could not decode, e.g. of a kind we don't know
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("request from {} failed: {}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Handshake, Handshake>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<Handshake, Handshake>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request { .. } if !self.peer_manager.allow_request(&peer, Instant::now()) => {
+                    self.penalize(peer, Misbehavior::TooManyRequests);
+                }
+                RequestResponseMessage::Request { request, channel, .. } => {
+                    // Answer even incompatible peers, so they can tell why we hang up.
+                    // Only the side reading a response closes the connection, at that point
+                    // both handshakes have been seen by both sides.
+                    self.metrics.received("Handshake");
+                    let local = Handshake::from_node(&self.node);
+                    self.metrics.sent("Handshake");
+                    if self.handshake.send_response(channel, local).is_err() {
+                        debug!("peer {} went away during handshake", peer);
+                    }
+                    self.on_handshake(peer, request);
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    self.metrics.received("Handshake");
+                    if !self.on_handshake(peer, response) {
+                        self.disconnect_queue.push(peer);
                     }
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                self.node.blockchain.add_block(block);
+            },
+            // Not an elemchain node at all
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                error: OutboundFailure::UnsupportedProtocols,
+                ..
+            } => {
+                info!("disconnecting {}: does not speak our protocol", peer);
+                self.mark_incompatible(peer);
+                self.disconnect_queue.push(peer);
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!("handshake with {} failed: {}", peer, error);
+            }
+            RequestResponseEvent::InboundFailure { .. } | RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated { peer, addresses, .. } = event {
+            for addr in addresses.iter() {
+                self.chain_exchange.add_address(&peer, addr.clone());
+            }
+            if !self.peers.contains_key(&peer) && !self.incompatible.contains(&peer) {
+                self.dial_queue.push(peer);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            let now = address_book::unix_now();
+            for addr in info.listen_addrs {
+                self.address_book.record(peer_id, Some(&addr), now);
+                self.add_peer_address(peer_id, addr);
             }
         }
     }
@@ -101,27 +811,261 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
-                for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
+                for (peer, addr) in discovered_list {
+                    if self.incompatible.contains(&peer) {
+                        continue;
+                    }
+                    self.add_peer_address(peer, addr.clone());
+                    self.dial_queue.push(peer);
                 }
             }
             MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
+                for (peer, addr) in expired_list {
+                    self.chain_exchange.remove_address(&peer, &addr);
                 }
             }
         }
     }
 }
 
+pub async fn swarm_factory(
+    id_keys: Keypair,
+    node: Node,
+    config: &NetworkConfig,
+) -> SwarmBuilder<AppBehaviour> {
+    let peer_id = PeerId::from(id_keys.public());
+
+    let auth_keys = noise::Keypair::<X25519Spec>::new()
+        .into_authentic(&id_keys)
+        .expect("can create auth keys");
+
+    let transp = TokioTcpConfig::new()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+        .multiplex(mplex::MplexConfig::new())
+        .boxed();
+
+    let behaviour = AppBehaviour::new(id_keys, node, config).await;
+
+    SwarmBuilder::new(transp, behaviour, peer_id).executor(Box::new(|fut| {
+        spawn(fut);
+    }))
+}
+
+/// Builds the swarm with our persisted identity, starts listening and dials the peers we know.
+pub async fn start_swarm(node: Node, config: &NetworkConfig) -> Result<Swarm<AppBehaviour>, NodeError> {
+    let id_keys = match &config.key_file {
+        Some(path) => keyfile::load_or_generate(path).map_err(|error| NodeError::KeyFile {
+            path: path.clone(),
+            error,
+        })?,
+        None => Keypair::generate_ed25519(),
+    };
+    let mut swarm = swarm_factory(id_keys, node, config).await.build();
+
+    for addr in &config.listen_addrs {
+        Swarm::listen_on(&mut swarm, addr.clone()).map_err(|e| NodeError::Transport(e.to_string()))?;
+    }
+
+    for addr in &config.bootstrap_peers {
+        dial(&mut swarm, addr);
+    }
+    reconnect_known_peers(&mut swarm);
+    swarm.behaviour_mut().bootstrap_discovery();
+    Ok(swarm)
+}
+
 pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
+    swarm
+        .behaviour()
+        .connected_peers()
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Dials `addr`. A trailing `/p2p/<peer id>` is also handed to Kademlia.
+pub fn dial(swarm: &mut Swarm<AppBehaviour>, addr: &Multiaddr) {
+    let (peer, dial_addr) = discovery::split_peer_id(addr);
+    if let Some(peer) = peer {
+        swarm.behaviour_mut().add_peer_address(peer, dial_addr.clone());
+    }
+    if let Err(e) = swarm.dial_addr(dial_addr) {
+        warn!("could not dial {}: {:?}", addr, e);
+    }
+}
+
+/// Dials the most recently seen peers of the address book we are not connected to.
+pub fn reconnect_known_peers(swarm: &mut Swarm<AppBehaviour>) {
+    let known = swarm.behaviour().address_book.peers();
+    let local_peer_id = *swarm.local_peer_id();
+    let now = Instant::now();
+    for (peer, addrs) in known.into_iter().take(RECONNECT_PEERS) {
+        if peer == local_peer_id
+            || swarm.is_connected(&peer)
+            || addrs.is_empty()
+            || swarm.behaviour().peer_manager.is_banned(&peer, now)
+        {
+            continue;
+        }
+        for addr in addrs {
+            swarm.behaviour_mut().add_peer_address(peer, addr);
+        }
+        if let Err(e) = swarm.dial(&peer) {
+            debug!("could not reconnect to {}: {:?}", peer, e);
+        }
+    }
+}
+
+/// Drives the swarm for `duration`, for callers without their own event loop.
+pub async fn run_for(swarm: &mut Swarm<AppBehaviour>, duration: Duration) {
+    let deadline = sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => {
+                handle_swarm_event(swarm, event);
+            }
+        }
+    }
+}
+
+/// Keeps track of connections and starts the handshake with new peers.
+pub fn handle_swarm_event<E>(swarm: &mut Swarm<AppBehaviour>, event: SwarmEvent<(), E>) -> Option<EventType> {
+    match event {
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            endpoint,
+            num_established,
+        } => {
+            if swarm.behaviour().is_incompatible(&peer_id) {
+                let _ = swarm.disconnect_peer_id(peer_id);
+                return None;
+            }
+            let remote_addr = endpoint.get_remote_address().clone();
+            if let Err(refusal) = swarm
+                .behaviour_mut()
+                .peer_manager
+                .on_connected(peer_id, &remote_addr, Instant::now())
+            {
+                info!("refusing {}: {}", peer_id, refusal);
+                let _ = swarm.disconnect_peer_id(peer_id);
+                return None;
+            }
+
+            let now = address_book::unix_now();
+            match endpoint {
+                ConnectedPoint::Dialer { address } => {
+                    swarm.behaviour_mut().address_book.record(peer_id, Some(&address), now);
+                    swarm.behaviour_mut().add_peer_address(peer_id, address);
+                }
+                // The remote port of an inbound connection is not one we can dial,
+                // its listen addresses arrive with identify
+                ConnectedPoint::Listener { .. } => swarm.behaviour_mut().address_book.record(peer_id, None, now),
+            }
+            if num_established.get() == 1 {
+                swarm.behaviour_mut().start_handshake(peer_id);
+            }
+            None
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            endpoint,
+            num_established,
+            ..
+        } => {
+            let behaviour = swarm.behaviour_mut();
+            behaviour
+                .peer_manager
+                .on_disconnected(&peer_id, endpoint.get_remote_address(), num_established == 0);
+            if num_established == 0 {
+                behaviour.peers.remove(&peer_id);
+            }
+            None
+        }
+        SwarmEvent::NewListenAddr { address, .. } => {
+            info!("listening on {}", address);
+            None
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::compact_block::CompactBlock;
+    use crate::discovery::tests::local_swarm;
+    use crate::p2p;
+    use crate::params::ChainParams;
+    use crate::protocol::ChainResponse;
+    use libp2p::{futures::StreamExt, gossipsub::MessageAcceptance, PeerId};
+    use std::time::{Duration, Instant};
+    use tokio::{select, time::sleep};
+
+    #[tokio::test]
+    async fn test_incomplete_compact_block() {
+        let (mut swarm, _) = local_swarm(ChainParams::test()).await;
+        let behaviour = swarm.behaviour_mut();
+        let mut chain = behaviour.node.blockchain.clone();
+        chain.try_mine(vec![generate_blocks()[0].transactions[0].clone()]).unwrap();
+        let block = chain.chain.last().unwrap().clone();
+
+        // Held back from gossip until the missing transaction arrives
+        let relay = PeerId::random();
+        let compact = CompactBlock::from_block(&block, 1);
+        let acceptance = behaviour.handle_compact_block(relay, compact);
+        assert!(matches!(acceptance, MessageAcceptance::Ignore));
+        assert!(behaviour.partial_blocks.contains(&block.hash));
+
+        // A relay which lacks the block is not at fault, the block is synced instead
+        let reply = ChainResponse::BlockTransactions {
+            block_hash: block.hash.clone(),
+            txs: vec![],
+        };
+        behaviour.handle_response(relay, reply);
+        assert!(!behaviour.partial_blocks.contains(&block.hash));
+        assert_eq!(behaviour.peer_manager.score(&relay, Instant::now()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_rebuilds_mined_block() {
+        let (mut miner, miner_addr) = local_swarm(ChainParams::test()).await;
+        let (mut peer, _) = local_swarm(ChainParams::test()).await;
+        let peer_id = *peer.local_peer_id();
+        let topic = miner.behaviour().blockchain_topic.hash();
+
+        p2p::dial(&mut peer, &miner_addr);
+        let deadline = sleep(Duration::from_secs(30));
+        tokio::pin!(deadline);
+        while !miner.behaviour().connected_peers().contains(&peer_id)
+            || !miner.behaviour().gossipsub.mesh_peers(&topic).any(|id| *id == peer_id)
+        {
+            select! {
+                e = miner.select_next_some() => { p2p::handle_swarm_event(&mut miner, e); },
+                e = peer.select_next_some() => { p2p::handle_swarm_event(&mut peer, e); },
+                _ = sleep(Duration::from_millis(100)) => {},
+                _ = &mut deadline => panic!("peers never joined the same mesh"),
+            }
+        }
+
+        // The peer already has one of the transactions and has to ask for the other
+        let known = generate_blocks()[0].transactions[0].clone();
+        let mut unknown = known.clone();
+        unknown.amount += 1;
+        peer.behaviour_mut().node.add_pending_tx(known.clone());
+        assert!(miner.behaviour_mut().node.blockchain.try_mine(vec![known, unknown]).is_ok());
+        miner.behaviour_mut().publish_tip_block();
 
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+        while peer.behaviour().node.blockchain != miner.behaviour().node.blockchain {
+            select! {
+                e = miner.select_next_some() => { p2p::handle_swarm_event(&mut miner, e); },
+                e = peer.select_next_some() => { p2p::handle_swarm_event(&mut peer, e); },
+                _ = sleep(Duration::from_millis(100)) => {},
+                _ = &mut deadline => panic!("peer never rebuilt the mined block"),
+            }
+        }
+        assert!(peer.behaviour().node.pending_txs.is_empty());
     }
-    unique_peers.iter().map(|p| p.to_string()).collect()
 }
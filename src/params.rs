@@ -0,0 +1,87 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::{block::Block, blockchain::Blockchain};
+
+pub const MAIN_NETWORK: &str = "main";
+pub const TEST_NETWORK: &str = "test";
+
+// Everything nodes of one network have to agree on. Nodes exchange the network id
+// and genesis hash when connecting and drop peers which differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub network_id: String,
+    pub difficulty: usize,
+    pub min_tx_per_block: u8,
+    /// Unix seconds of the genesis block, different for every network.
+    pub genesis_time: u64,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams::main()
+    }
+}
+
+impl ChainParams {
+    pub fn main() -> Self {
+        ChainParams {
+            network_id: String::from(MAIN_NETWORK),
+            difficulty: 3,
+            min_tx_per_block: 0,
+            genesis_time: 1_640_995_200,
+        }
+    }
+
+    /// Easier blocks for trying things out.
+    pub fn test() -> Self {
+        ChainParams {
+            network_id: String::from(TEST_NETWORK),
+            difficulty: 2,
+            min_tx_per_block: 0,
+            genesis_time: 1_641_081_600,
+        }
+    }
+
+    pub fn by_network_id(network_id: &str) -> Option<Self> {
+        match network_id {
+            MAIN_NETWORK => Some(ChainParams::main()),
+            TEST_NETWORK => Some(ChainParams::test()),
+            _ => None,
+        }
+    }
+
+    /// The first block of the network. It is fixed rather than mined,
+    /// so it does not have to meet the difficulty.
+    pub fn genesis_block(&self) -> Block {
+        let time = UNIX_EPOCH + Duration::from_secs(self.genesis_time);
+        let mut block = Block::new(String::new(), vec![], 0, time);
+        block.generate_hash();
+        block
+    }
+
+    /// A chain holding only the genesis block.
+    pub fn new_chain(&self, concurrent_hashes: u64) -> Blockchain {
+        let mut chain = Blockchain::new(self.min_tx_per_block, self.difficulty, concurrent_hashes);
+        chain.chain.push(self.genesis_block());
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::params::ChainParams;
+
+    #[test]
+    fn test_genesis() {
+        let main = ChainParams::main();
+        assert_eq!(main.genesis_block(), main.genesis_block());
+        assert!(main.genesis_block().has_valid_hash());
+        assert_ne!(main.genesis_block().hash, ChainParams::test().genesis_block().hash);
+
+        let chain = main.new_chain(256);
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain.difficulty(), main.difficulty);
+        assert_eq!(ChainParams::by_network_id("test"), Some(ChainParams::test()));
+        assert_eq!(ChainParams::by_network_id("nope"), None);
+    }
+}
@@ -0,0 +1,375 @@
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Misbehavior score at which a peer gets banned.
+pub const BAN_THRESHOLD: u32 = 100;
+pub const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+/// Scores go down by one point per this long, so rare mistakes are forgiven.
+pub const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+/// Connections allowed from one IP. Loopback is not limited, to run several nodes locally.
+pub const MAX_CONNECTIONS_PER_IP: usize = 4;
+/// Requests a peer may send per `RATE_LIMIT_WINDOW`.
+pub const MAX_REQUESTS_PER_WINDOW: u32 = 60;
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+/// Scores remembered per peer id and per IP, the lowest are forgotten first.
+pub const MAX_SCORES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    InvalidBlock,
+    InvalidHeaders,
+    InvalidTransaction,
+    InvalidMessage,
+    OversizedMessage,
+    TooManyRequests,
+}
+
+impl Misbehavior {
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock | Misbehavior::InvalidHeaders => 50,
+            Misbehavior::OversizedMessage => 40,
+            Misbehavior::InvalidMessage => 20,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::TooManyRequests => 5,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Misbehavior::InvalidBlock => "invalid block",
+            Misbehavior::InvalidHeaders => "invalid headers",
+            Misbehavior::InvalidTransaction => "invalid transaction",
+            Misbehavior::InvalidMessage => "invalid message",
+            Misbehavior::OversizedMessage => "oversized message",
+            Misbehavior::TooManyRequests => "too many requests",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    Banned { remaining: Duration },
+    TooManyConnectionsFromIp(IpAddr),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Banned { remaining } => write!(f, "banned for another {}s", remaining.as_secs()),
+            Refusal::TooManyConnectionsFromIp(ip) => write!(f, "too many connections from {}", ip),
+        }
+    }
+}
+
+/// What we know about a connected peer's behaviour, for the peer info view.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: Multiaddr,
+    pub connected_since: Instant,
+    pub last_offense: Option<Misbehavior>,
+    requests_in_window: u32,
+    window_start: Instant,
+    pub total_requests: u64,
+    // IPs of the connections counted towards the per IP limit
+    counted_ips: Vec<IpAddr>,
+}
+
+impl PeerInfo {
+    fn new(addr: Multiaddr, now: Instant) -> Self {
+        PeerInfo {
+            addr,
+            connected_since: now,
+            last_offense: None,
+            requests_in_window: 0,
+            window_start: now,
+            total_requests: 0,
+            counted_ips: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    points: u32,
+    scored_at: Instant,
+}
+
+impl Score {
+    /// Points left after decay.
+    fn at(&self, now: Instant) -> u32 {
+        let decay = now.duration_since(self.scored_at).as_secs() / SCORE_DECAY_INTERVAL.as_secs();
+        self.points.saturating_sub(decay as u32)
+    }
+}
+
+/// Adds `penalty` to the score of `key`, making room if there are `MAX_SCORES` already.
+/// Returns the new score.
+fn add_penalty<K: Hash + Eq + Copy>(scores: &mut HashMap<K, Score>, key: K, penalty: u32, now: Instant) -> u32 {
+    if !scores.contains_key(&key) && scores.len() >= MAX_SCORES {
+        let lowest = scores.iter().min_by_key(|(_, score)| score.at(now)).map(|(key, _)| *key);
+        if let Some(lowest) = lowest {
+            scores.remove(&lowest);
+        }
+    }
+    let score = scores.entry(key).or_insert(Score { points: 0, scored_at: now });
+    score.points = score.at(now) + penalty;
+    score.scored_at = now;
+    score.points
+}
+
+// Keeps misbehavior scores, bans and per IP connection counts. Connections are only
+// counted here, closing them is left to the swarm owner. Scores outlive connections,
+// so reconnecting doesn't wipe them, and decay instead.
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    peers: HashMap<PeerId, PeerInfo>,
+    peer_scores: HashMap<PeerId, Score>,
+    ip_scores: HashMap<IpAddr, Score>,
+    banned_peers: HashMap<PeerId, Instant>,
+    banned_ips: HashMap<IpAddr, Instant>,
+    connections_per_ip: HashMap<IpAddr, usize>,
+}
+
+pub fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+impl PeerManager {
+    /// How much longer `peer` at `addr` is banned for, if at all.
+    pub fn ban_remaining(&self, peer: &PeerId, addr: Option<&Multiaddr>, now: Instant) -> Option<Duration> {
+        let ip_ban = addr.and_then(ip_of).and_then(|ip| self.banned_ips.get(&ip));
+        [self.banned_peers.get(peer), ip_ban]
+            .iter()
+            .flatten()
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+            .max()
+    }
+
+    pub fn is_banned(&self, peer: &PeerId, now: Instant) -> bool {
+        self.ban_remaining(peer, None, now).is_some()
+    }
+
+    /// Counts a new connection, or tells why it should be closed right away.
+    pub fn on_connected(&mut self, peer: PeerId, addr: &Multiaddr, now: Instant) -> Result<(), Refusal> {
+        if let Some(remaining) = self.ban_remaining(&peer, Some(addr), now) {
+            return Err(Refusal::Banned { remaining });
+        }
+        let limited_ip = ip_of(addr).filter(|ip| !ip.is_loopback());
+        if let Some(ip) = limited_ip {
+            if self.connections_per_ip.get(&ip).copied().unwrap_or(0) >= MAX_CONNECTIONS_PER_IP {
+                return Err(Refusal::TooManyConnectionsFromIp(ip));
+            }
+            *self.connections_per_ip.entry(ip).or_insert(0) += 1;
+        }
+        let info = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| PeerInfo::new(addr.clone(), now));
+        info.counted_ips.extend(limited_ip);
+        Ok(())
+    }
+
+    /// Forgets one connection of `peer`, and the peer itself with its last one. Its score stays.
+    pub fn on_disconnected(&mut self, peer: &PeerId, addr: &Multiaddr, last_connection: bool) {
+        let info = match self.peers.get_mut(peer) {
+            Some(info) => info,
+            // Refused right away, nothing was counted
+            None => return,
+        };
+        let counted = ip_of(addr).and_then(|ip| info.counted_ips.iter().position(|counted| *counted == ip));
+        if let Some(index) = counted {
+            let ip = info.counted_ips.remove(index);
+            if let Some(count) = self.connections_per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    self.connections_per_ip.remove(&ip);
+                }
+            }
+        }
+        if last_connection {
+            self.peers.remove(peer);
+        }
+    }
+
+    /// Adds the penalty for `offense` to the peer's score. Returns true if the peer is banned now.
+    pub fn misbehaved(&mut self, peer: &PeerId, offense: Misbehavior, now: Instant) -> bool {
+        let info = match self.peers.get_mut(peer) {
+            Some(info) => info,
+            None => return false,
+        };
+        info.last_offense = Some(offense);
+        let ip = ip_of(&info.addr).filter(|ip| !ip.is_loopback());
+        let mut score = add_penalty(&mut self.peer_scores, *peer, offense.penalty(), now);
+        if let Some(ip) = ip {
+            score = score.max(add_penalty(&mut self.ip_scores, ip, offense.penalty(), now));
+        }
+        if score < BAN_THRESHOLD {
+            return false;
+        }
+
+        let until = now + BAN_DURATION;
+        self.banned_peers.insert(*peer, until);
+        if let Some(ip) = ip {
+            self.banned_ips.insert(ip, until);
+        }
+        true
+    }
+
+    /// Misbehavior score of `peer` after decay, connected or not.
+    pub fn score(&self, peer: &PeerId, now: Instant) -> u32 {
+        self.peer_scores.get(peer).map(|score| score.at(now)).unwrap_or(0)
+    }
+
+    /// Counts a request from `peer`, false if it is over its rate limit.
+    pub fn allow_request(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let info = match self.peers.get_mut(peer) {
+            Some(info) => info,
+            None => return false,
+        };
+        if now.duration_since(info.window_start) >= RATE_LIMIT_WINDOW {
+            info.window_start = now;
+            info.requests_in_window = 0;
+        }
+        info.requests_in_window += 1;
+        info.total_requests += 1;
+        info.requests_in_window <= MAX_REQUESTS_PER_WINDOW
+    }
+
+    pub fn peer_info(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer)
+    }
+
+    /// Banned peers with the time left on their ban.
+    pub fn bans(&self, now: Instant) -> Vec<(PeerId, Duration)> {
+        self.banned_peers
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(peer, until)| (*peer, until.duration_since(now)))
+            .collect()
+    }
+
+    /// Drops expired bans and fully decayed scores. Called on the sync timer.
+    pub fn prune(&mut self, now: Instant) {
+        self.banned_peers.retain(|_, until| *until > now);
+        self.banned_ips.retain(|_, until| *until > now);
+        self.peer_scores.retain(|_, score| score.at(now) > 0);
+        self.ip_scores.retain(|_, score| score.at(now) > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer_manager::{
+        Misbehavior, PeerManager, Refusal, BAN_DURATION, BAN_THRESHOLD, MAX_CONNECTIONS_PER_IP, MAX_REQUESTS_PER_WINDOW,
+        RATE_LIMIT_WINDOW, SCORE_DECAY_INTERVAL,
+    };
+    use libp2p::{Multiaddr, PeerId};
+    use std::time::Instant;
+
+    fn addr(ip: &str) -> Multiaddr {
+        format!("/ip4/{}/tcp/4001", ip).parse().unwrap()
+    }
+
+    #[test]
+    fn test_ban() {
+        let mut manager = PeerManager::default();
+        let now = Instant::now();
+        let peer = PeerId::random();
+        manager.on_connected(peer, &addr("10.0.0.1"), now).unwrap();
+
+        assert!(!manager.misbehaved(&peer, Misbehavior::InvalidBlock, now));
+        // Half of the score decayed, so this is not enough yet
+        let later = now + SCORE_DECAY_INTERVAL * 25;
+        assert!(!manager.misbehaved(&peer, Misbehavior::InvalidBlock, later));
+        assert!(manager.misbehaved(&peer, Misbehavior::InvalidHeaders, later));
+        assert!(manager.is_banned(&peer, later));
+        assert_eq!(manager.bans(later).len(), 1);
+
+        // Same IP with a new peer id is refused too
+        manager.on_disconnected(&peer, &addr("10.0.0.1"), true);
+        let other = PeerId::random();
+        assert!(matches!(
+            manager.on_connected(other, &addr("10.0.0.1"), later),
+            Err(Refusal::Banned { .. })
+        ));
+
+        let expired = later + BAN_DURATION;
+        assert!(!manager.is_banned(&peer, expired));
+        assert!(manager.bans(expired).is_empty());
+        assert!(manager.on_connected(peer, &addr("10.0.0.1"), expired).is_ok());
+    }
+
+    #[test]
+    fn test_score_survives_reconnect() {
+        let mut manager = PeerManager::default();
+        let now = Instant::now();
+        let peer = PeerId::random();
+        manager.on_connected(peer, &addr("10.0.0.4"), now).unwrap();
+        assert!(!manager.misbehaved(&peer, Misbehavior::InvalidBlock, now));
+        manager.on_disconnected(&peer, &addr("10.0.0.4"), true);
+        assert_eq!(manager.score(&peer, now), 50);
+
+        // Neither a new connection nor a new peer id from the same IP starts over
+        let other = PeerId::random();
+        manager.on_connected(other, &addr("10.0.0.4"), now).unwrap();
+        assert!(manager.misbehaved(&other, Misbehavior::InvalidHeaders, now));
+
+        let decayed = now + SCORE_DECAY_INTERVAL * BAN_THRESHOLD;
+        manager.prune(decayed);
+        assert_eq!(manager.score(&peer, decayed), 0);
+        assert!(manager.peer_scores.is_empty() && manager.ip_scores.is_empty());
+        assert!(manager.banned_peers.is_empty() && manager.banned_ips.is_empty());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut manager = PeerManager::default();
+        let now = Instant::now();
+        for _ in 0..MAX_CONNECTIONS_PER_IP {
+            manager.on_connected(PeerId::random(), &addr("10.0.0.2"), now).unwrap();
+        }
+        let peer = PeerId::random();
+        assert!(matches!(
+            manager.on_connected(peer, &addr("10.0.0.2"), now),
+            Err(Refusal::TooManyConnectionsFromIp(_))
+        ));
+        assert!(manager.on_connected(peer, &addr("127.0.0.1"), now).is_ok());
+        assert!(manager.on_connected(PeerId::random(), &addr("127.0.0.1"), now).is_ok());
+
+        // The refused connection closing must not free a slot
+        manager.on_disconnected(&peer, &addr("10.0.0.2"), false);
+        assert!(manager.on_connected(PeerId::random(), &addr("10.0.0.2"), now).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut manager = PeerManager::default();
+        let now = Instant::now();
+        let peer = PeerId::random();
+        assert!(!manager.allow_request(&peer, now));
+
+        manager.on_connected(peer, &addr("10.0.0.3"), now).unwrap();
+        for _ in 0..MAX_REQUESTS_PER_WINDOW {
+            assert!(manager.allow_request(&peer, now));
+        }
+        assert!(!manager.allow_request(&peer, now));
+        assert!(manager.allow_request(&peer, now + RATE_LIMIT_WINDOW));
+        assert_eq!(
+            manager.peer_info(&peer).unwrap().total_requests,
+            MAX_REQUESTS_PER_WINDOW as u64 + 2
+        );
+    }
+}
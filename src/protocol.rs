@@ -0,0 +1,298 @@
+use async_trait::async_trait;
+use libp2p::{
+    core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName},
+    futures::{io, AsyncRead, AsyncWrite, AsyncWriteExt},
+    request_response::{ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig},
+};
+use serde::{Deserialize, Serialize};
+use std::iter;
+use std::time::Duration;
+
+use crate::{
+    block::{encoded_size, Block, BlockHeader},
+    message::{
+        chunk_blocks, MessageError, NetworkMessage, MAX_CHUNK_SIZE, MAX_FRAME_SIZE, MAX_REQUEST_SIZE,
+    },
+    transaction::Transaction,
+};
+
+pub const CHAIN_EXCHANGE_PROTOCOL: &[u8] = b"/elemchain/chain-exchange/1.0.0";
+/// Total size of the blocks streamed back for one request.
+pub const MAX_BLOCKS_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Requests go to exactly one peer over their own substream, instead of being flooded to everyone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum ChainRequest {
+    GetHeaders { locator: Vec<String> },
+    GetBlocks { hashes: Vec<String> },
+    GetTransactions { ids: Vec<String> },
+    /// Transactions of a compact block we could not find in our pool, by position in the block.
+    GetBlockTransactions { block_hash: String, indexes: Vec<usize> },
+    /// A new transaction in its stem phase, for this peer only.
+    StemTransaction { tx: Transaction },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChainResponse {
+    Headers(Vec<BlockHeader>),
+    Blocks(Vec<Block>),
+    Transactions(Vec<Transaction>),
+    BlockTransactions { block_hash: String, txs: Vec<Transaction> },
+    /// Answer to requests which only deliver something.
+    Ack,
+}
+
+impl ChainRequest {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChainRequest::GetHeaders { .. } => "GetHeaders",
+            ChainRequest::GetBlocks { .. } => "GetBlocks",
+            ChainRequest::GetTransactions { .. } => "GetTransactions",
+            ChainRequest::GetBlockTransactions { .. } => "GetBlockTransactions",
+            ChainRequest::StemTransaction { .. } => "StemTransaction",
+        }
+    }
+}
+
+impl ChainResponse {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChainResponse::Headers(_) => "Headers",
+            ChainResponse::Blocks(_) => "Blocks",
+            ChainResponse::Transactions(_) => "Transactions",
+            ChainResponse::BlockTransactions { .. } => "BlockTransactions",
+            ChainResponse::Ack => "Ack",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainExchangeProtocol;
+
+impl ProtocolName for ChainExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        CHAIN_EXCHANGE_PROTOCOL
+    }
+}
+
+#[derive(Clone)]
+pub struct ChainExchangeCodec;
+
+/// Reads one length prefixed message of at most `max_size` bytes.
+pub async fn read_message<T>(io: &mut T, max_size: usize) -> io::Result<NetworkMessage>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let data = read_length_prefixed(io, max_size).await?;
+    if data.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    NetworkMessage::decode(&data).map_err(invalid_data)
+}
+
+pub async fn write_message<T>(io: &mut T, msg: &NetworkMessage) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    write_length_prefixed(io, msg.encode()).await?;
+    io.close().await
+}
+
+/// Writes blocks as a stream of `BlockChunk` frames, ended by an empty chunk.
+async fn write_block_chunks<T>(io: &mut T, blocks: Vec<Block>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    for chunk in chunk_blocks(blocks) {
+        write_length_prefixed(io, NetworkMessage::BlockChunk(chunk).encode()).await?;
+    }
+    write_message(io, &NetworkMessage::BlockChunk(vec![])).await
+}
+
+/// Reads the rest of a block stream started by `first`.
+async fn read_block_chunks<T>(io: &mut T, first: Vec<Block>) -> io::Result<Vec<Block>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut size = encoded_size(&first);
+    let mut blocks = first;
+    loop {
+        let chunk = match read_message(io, MAX_CHUNK_SIZE).await? {
+            NetworkMessage::BlockChunk(chunk) => chunk,
+            other => return Err(invalid_data(MessageError::UnexpectedKind(other.kind()))),
+        };
+        if chunk.is_empty() {
+            return Ok(blocks);
+        }
+        size += encoded_size(&chunk);
+        if size > MAX_BLOCKS_RESPONSE_SIZE {
+            return Err(invalid_data(MessageError::TooLarge {
+                kind: "BlockChunk",
+                size,
+                max: MAX_BLOCKS_RESPONSE_SIZE,
+            }));
+        }
+        blocks.extend(chunk);
+    }
+}
+
+/// The leading blocks which fit into one response, the rest has to be asked for again.
+pub fn blocks_within_limit(blocks: Vec<Block>) -> Vec<Block> {
+    let mut size = 0;
+    blocks
+        .into_iter()
+        .take_while(|block| {
+            size += encoded_size(block);
+            size <= MAX_BLOCKS_RESPONSE_SIZE
+        })
+        .collect()
+}
+
+pub fn invalid_data(e: MessageError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[async_trait]
+impl RequestResponseCodec for ChainExchangeCodec {
+    type Protocol = ChainExchangeProtocol;
+    type Request = ChainRequest;
+    type Response = ChainResponse;
+
+    async fn read_request<T>(&mut self, _: &ChainExchangeProtocol, io: &mut T) -> io::Result<ChainRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match read_message(io, MAX_REQUEST_SIZE).await? {
+            NetworkMessage::Request(request) => Ok(request),
+            other => Err(invalid_data(MessageError::UnexpectedKind(other.kind()))),
+        }
+    }
+
+    async fn read_response<T>(&mut self, _: &ChainExchangeProtocol, io: &mut T) -> io::Result<ChainResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match read_message(io, MAX_FRAME_SIZE).await? {
+            NetworkMessage::Response(response) => Ok(response),
+            NetworkMessage::BlockChunk(first) if !first.is_empty() => {
+                read_block_chunks(io, first).await.map(ChainResponse::Blocks)
+            }
+            NetworkMessage::BlockChunk(_) => Ok(ChainResponse::Blocks(vec![])),
+            other => Err(invalid_data(MessageError::UnexpectedKind(other.kind()))),
+        }
+    }
+
+    async fn write_request<T>(&mut self, _: &ChainExchangeProtocol, io: &mut T, req: ChainRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &NetworkMessage::Request(req)).await
+    }
+
+    async fn write_response<T>(&mut self, _: &ChainExchangeProtocol, io: &mut T, res: ChainResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match res {
+            ChainResponse::Blocks(blocks) => write_block_chunks(io, blocks).await,
+            res => write_message(io, &NetworkMessage::Response(res)).await,
+        }
+    }
+}
+
+pub fn new_chain_exchange() -> RequestResponse<ChainExchangeCodec> {
+    let mut cfg = RequestResponseConfig::default();
+    cfg.set_request_timeout(REQUEST_TIMEOUT);
+
+    RequestResponse::new(
+        ChainExchangeCodec,
+        iter::once((ChainExchangeProtocol, ProtocolSupport::Full)),
+        cfg,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::{encoded_size, tests::generate_blocks, MAX_BLOCK_SIZE};
+    use crate::message::NetworkMessage;
+    use crate::protocol::{
+        blocks_within_limit, ChainExchangeCodec, ChainExchangeProtocol, ChainRequest, ChainResponse,
+        MAX_BLOCKS_RESPONSE_SIZE,
+    };
+    use libp2p::core::upgrade::write_length_prefixed;
+    use libp2p::futures::io::Cursor;
+    use libp2p::request_response::RequestResponseCodec;
+
+    #[tokio::test]
+    async fn test_codec_roundtrip() {
+        let mut codec = ChainExchangeCodec;
+
+        let request = ChainRequest::GetBlocks {
+            hashes: vec![String::from("123"), String::from("new_hash")],
+        };
+        let mut buf = Cursor::new(vec![]);
+        codec
+            .write_request(&ChainExchangeProtocol, &mut buf, request.clone())
+            .await
+            .unwrap();
+        buf.set_position(0);
+        let read = codec.read_request(&ChainExchangeProtocol, &mut buf).await.unwrap();
+        assert_eq!(read, request);
+
+        let response = ChainResponse::Blocks(generate_blocks());
+        let mut buf = Cursor::new(vec![]);
+        codec
+            .write_response(&ChainExchangeProtocol, &mut buf, response.clone())
+            .await
+            .unwrap();
+        buf.set_position(0);
+        let read = codec.read_response(&ChainExchangeProtocol, &mut buf).await.unwrap();
+        assert_eq!(read, response);
+    }
+
+    #[tokio::test]
+    async fn test_codec_rejects_garbage() {
+        let mut codec = ChainExchangeCodec;
+        let mut buf = Cursor::new(vec![4, b'n', b'o', b'p', b'e']);
+        assert!(codec.read_request(&ChainExchangeProtocol, &mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_streamed_in_chunks() {
+        let mut codec = ChainExchangeCodec;
+        let block = generate_blocks().remove(0);
+        let blocks = vec![block.clone(); 2 * MAX_BLOCK_SIZE / encoded_size(&block)];
+
+        let mut buf = Cursor::new(vec![]);
+        codec
+            .write_response(&ChainExchangeProtocol, &mut buf, ChainResponse::Blocks(blocks.clone()))
+            .await
+            .unwrap();
+        assert!(buf.get_ref().len() > MAX_BLOCK_SIZE * 2);
+        buf.set_position(0);
+        let read = codec.read_response(&ChainExchangeProtocol, &mut buf).await.unwrap();
+        assert_eq!(read, ChainResponse::Blocks(blocks));
+
+        // A stream must not end with something else than a chunk
+        let mut buf = Cursor::new(vec![]);
+        write_length_prefixed(&mut buf, NetworkMessage::BlockChunk(vec![block.clone()]).encode())
+            .await
+            .unwrap();
+        write_length_prefixed(&mut buf, NetworkMessage::Response(ChainResponse::Headers(vec![])).encode())
+            .await
+            .unwrap();
+        buf.set_position(0);
+        assert!(codec.read_response(&ChainExchangeProtocol, &mut buf).await.is_err());
+    }
+
+    #[test]
+    fn test_blocks_within_limit() {
+        let block = generate_blocks().remove(0);
+        let fitting = MAX_BLOCKS_RESPONSE_SIZE / encoded_size(&block);
+        let blocks = vec![block; fitting + 10];
+        assert_eq!(blocks_within_limit(blocks.clone()).len(), fitting);
+        assert_eq!(blocks_within_limit(blocks[..3].to_vec()), blocks[..3].to_vec());
+    }
+}
@@ -0,0 +1,171 @@
+use log::debug;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    http::{self, Request, Response},
+    p2p::AppBehaviour,
+    rpc,
+};
+
+pub const DEFAULT_REST_ADDR: &str = "127.0.0.1:8080";
+
+/// A GET for the node's event loop, answered with a status and a JSON body on `reply`.
+#[derive(Debug)]
+pub struct RestCall {
+    pub path: String,
+    pub reply: oneshot::Sender<(u16, Value)>,
+}
+
+/// Answers read-only explorer requests on `listener`, looking them up in the node.
+pub async fn serve(listener: TcpListener, calls: mpsc::UnboundedSender<RestCall>) {
+    http::serve(listener, move |request| {
+        let calls = calls.clone();
+        async move { handle_http(&request, &calls).await }
+    })
+    .await
+}
+
+async fn handle_http(request: &Request, calls: &mpsc::UnboundedSender<RestCall>) -> Response {
+    if request.method != "GET" {
+        return Response::empty(405);
+    }
+    let (reply, result) = oneshot::channel();
+    let call = RestCall {
+        path: request.path.clone(),
+        reply,
+    };
+    let (status, body) = match calls.send(call) {
+        Ok(()) => result.await.ok(),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| error(503, "node is shutting down"));
+    let mut response = Response::json(status, &body);
+    // Nothing here is secret, so an explorer may be served from anywhere
+    response
+        .headers
+        .push((String::from("Access-Control-Allow-Origin"), String::from("*")));
+    response
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+/// Looks up `path` in the node. Called from the event loop.
+pub fn handle_get(behaviour: &AppBehaviour, path: &str) -> (u16, Value) {
+    debug!("rest {}", path);
+    let node = &behaviour.node;
+    let chain = &node.blockchain;
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    let found = |value: Option<Value>, what: &str| match value {
+        Some(value) => (200, value),
+        None => error(404, &format!("{} not found", what)),
+    };
+    match segments.as_slice() {
+        ["blocks", "height", height] => match height.parse() {
+            Ok(height) => found(rpc::block_info(chain, height), "block"),
+            Err(_) => error(400, "height has to be a number"),
+        },
+        ["blocks", hash] => found(chain.height_of(hash).and_then(|height| rpc::block_info(chain, height)), "block"),
+        ["chain", "tip"] => found(rpc::block_info(chain, chain.len() - 1), "block"),
+        ["tx", id] => found(rpc::transaction_info(node, id), "transaction"),
+        ["address", address] => {
+            let confirmed: Vec<Value> = chain
+                .chain
+                .iter()
+                .enumerate()
+                .flat_map(|(height, block)| block.transactions.iter().map(move |tx| (height, block, tx)))
+                .filter(|(_, _, tx)| tx.from == *address || tx.to == *address)
+                .map(|(height, block, tx)| json!({ "tx": tx, "height": height, "blockhash": block.hash }))
+                .collect();
+            let pending: Vec<_> = node
+                .pending_txs
+                .iter()
+                .filter(|tx| tx.from == *address || tx.to == *address)
+                .collect();
+            (
+                200,
+                json!({
+                    "address": address,
+                    "balance": chain.balance(address),
+                    "transactions": confirmed,
+                    "pending": pending,
+                }),
+            )
+        }
+        ["mempool"] => {
+            let mut info = rpc::mempool_info(node);
+            info["transactions"] = json!(node.pending_txs);
+            (200, info)
+        }
+        ["peers"] => (200, rpc::peer_info(behaviour)),
+        _ => error(404, "not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::discovery::tests::local_swarm;
+    use crate::params::ChainParams;
+    use crate::rest::{handle_get, serve, RestCall};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_routes() {
+        let (mut swarm, _) = local_swarm(ChainParams::test()).await;
+        let tx = generate_blocks()[0].transactions[0].clone();
+        swarm.behaviour_mut().submit_transaction(tx.clone());
+        let hash = swarm.behaviour_mut().mine_block().unwrap();
+        let behaviour = swarm.behaviour();
+
+        let (status, tip) = handle_get(behaviour, "/chain/tip");
+        assert_eq!((status, tip["height"].clone()), (200, json!(1)));
+        assert_eq!(tip["block"]["hash"], hash);
+        assert_eq!(handle_get(behaviour, &format!("/blocks/{}", hash)), (200, tip.clone()));
+        assert_eq!(handle_get(behaviour, "/blocks/height/1/"), (200, tip));
+        assert_eq!(handle_get(behaviour, "/blocks/height/9").0, 404);
+        assert_eq!(handle_get(behaviour, "/blocks/height/x").0, 400);
+
+        let (_, confirmed) = handle_get(behaviour, &format!("/tx/{}", tx.id()));
+        assert_eq!(confirmed["blockhash"], hash);
+        let (_, address) = handle_get(behaviour, &format!("/address/{}", tx.to));
+        assert_eq!(address["balance"], tx.amount);
+        assert_eq!(address["transactions"][0]["height"], 1);
+        assert_eq!(handle_get(behaviour, "/mempool").1["transactions"], json!([]));
+        assert_eq!(handle_get(behaviour, "/peers"), (200, json!([])));
+        assert_eq!(handle_get(behaviour, "/blocks").0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let (swarm, _) = local_swarm(ChainParams::test()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (calls, mut call_rcv) = mpsc::unbounded_channel::<RestCall>();
+        tokio::spawn(serve(listener, calls));
+        tokio::spawn(async move {
+            while let Some(call) = call_rcv.recv().await {
+                let _ = call.reply.send(handle_get(swarm.behaviour(), &call.path));
+            }
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"POST /chain/tip HTTP/1.1\r\n\r\nGET /chain/tip HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+        let (_, tip) = response.rsplit_once("\r\n\r\n").unwrap();
+        let tip: Value = serde_json::from_str(tip).unwrap();
+        assert_eq!(tip["height"], 0);
+        assert!(response.contains("Access-Control-Allow-Origin: *"));
+    }
+}
@@ -0,0 +1,468 @@
+use libp2p::gossipsub::MessageAcceptance;
+use log::debug;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use std::fmt;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    block::encoded_size,
+    blockchain::Blockchain,
+    gossip,
+    http::{self, Request, Response},
+    miner::MiningMode,
+    node::Node,
+    p2p::AppBehaviour,
+    transaction::Transaction,
+};
+
+pub const DEFAULT_RPC_ADDR: &str = "127.0.0.1:8545";
+
+// JSON-RPC 2.0 error codes, the application ones from the range left for servers
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const NOT_FOUND: i64 = -32001;
+pub const REJECTED: i64 = -32002;
+pub const MINING_FAILED: i64 = -32003;
+
+/// Blocks one `generate` call may mine, so a typo doesn't keep the miner busy for hours.
+pub const MAX_GENERATE: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "code": self.code, "message": self.message })
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Missing for notifications, which get no response. An explicit `null` is an id like any other.
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+}
+
+/// `Some` for every value given, `null` included, so only a missing field ends up `None`.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// A method call for the node's event loop, which owns the node. The result goes back on `reply`.
+#[derive(Debug)]
+pub struct RpcCall {
+    pub method: String,
+    pub params: Vec<Value>,
+    pub reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// Answers JSON-RPC requests on `listener`, passing the calls on to the node.
+pub async fn serve(listener: TcpListener, calls: mpsc::UnboundedSender<RpcCall>) {
+    http::serve(listener, move |request| {
+        let calls = calls.clone();
+        async move { handle_http(&request, &calls).await }
+    })
+    .await
+}
+
+async fn handle_http(request: &Request, calls: &mpsc::UnboundedSender<RpcCall>) -> Response {
+    if request.method != "POST" {
+        return Response::empty(405);
+    }
+    match handle_body(&request.body, calls).await {
+        Some(body) => Response::json(200, &body),
+        // Only notifications, nothing to answer
+        None => Response::empty(204),
+    }
+}
+
+/// Answers a single request or a batch, `None` if there is nothing to answer.
+pub async fn handle_body(body: &[u8], calls: &mpsc::UnboundedSender<RpcCall>) -> Option<Value> {
+    let parsed: Value = match serde_json::from_slice(body) {
+        Ok(parsed) => parsed,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    match parsed {
+        Value::Array(batch) if batch.is_empty() => {
+            Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+        }
+        Value::Array(batch) => {
+            let mut responses = vec![];
+            for request in batch {
+                responses.extend(handle_request(request, calls).await);
+            }
+            match responses.is_empty() {
+                true => None,
+                false => Some(Value::Array(responses)),
+            }
+        }
+        request => handle_request(request, calls).await,
+    }
+}
+
+async fn handle_request(request: Value, calls: &mpsc::UnboundedSender<RpcCall>) -> Option<Value> {
+    let request: RpcRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, e.to_string()))),
+    };
+    let id = request.id.clone();
+    let result = call(request, calls).await;
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+async fn call(request: RpcRequest, calls: &mpsc::UnboundedSender<RpcCall>) -> Result<Value, RpcError> {
+    if request.jsonrpc != "2.0" {
+        return Err(RpcError::new(INVALID_REQUEST, "jsonrpc has to be \"2.0\""));
+    }
+    let params = match request.params {
+        Value::Null => vec![],
+        Value::Array(params) => params,
+        _ => return Err(RpcError::new(INVALID_PARAMS, "params have to be an array")),
+    };
+    let (reply, result) = oneshot::channel();
+    let call = RpcCall {
+        method: request.method,
+        params,
+        reply,
+    };
+    calls
+        .send(call)
+        .map_err(|_| RpcError::new(INTERNAL_ERROR, "node is shutting down"))?;
+    result
+        .await
+        .unwrap_or_else(|_| Err(RpcError::new(INTERNAL_ERROR, "node is shutting down")))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error.to_json(), "id": id })
+}
+
+/// Answers `call` on its reply channel. Called from the event loop, which keeps running while
+/// `generate` waits for the background miner.
+pub fn answer(behaviour: &mut AppBehaviour, call: RpcCall) {
+    if call.method != "generate" {
+        let _ = call.reply.send(handle_call(behaviour, &call.method, &call.params));
+        return;
+    }
+    debug!("rpc generate");
+    let mined = match generate(behaviour, &call.params) {
+        Ok(mined) => mined,
+        Err(e) => {
+            let _ = call.reply.send(Err(e));
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let result = match mined.await {
+            Ok(mined) => Ok(json!(mined.into_iter().map(|(_, hash)| hash).collect::<Vec<_>>())),
+            Err(_) => Err(RpcError::new(MINING_FAILED, "mining was stopped before all blocks were mined")),
+        };
+        let _ = call.reply.send(result);
+    });
+}
+
+/// Hands `generate [n]` to the background miner.
+fn generate(
+    behaviour: &mut AppBehaviour,
+    params: &[Value],
+) -> Result<oneshot::Receiver<Vec<(usize, String)>>, RpcError> {
+    let blocks = match params.first() {
+        Some(blocks) => blocks
+            .as_u64()
+            .filter(|blocks| *blocks <= MAX_GENERATE)
+            .ok_or_else(|| {
+                RpcError::new(INVALID_PARAMS, format!("expected a number of blocks up to {}", MAX_GENERATE))
+            })?,
+        None => 1,
+    };
+    behaviour
+        .generate(blocks)
+        .map_err(|e| RpcError::new(MINING_FAILED, e.to_string()))
+}
+
+/// Runs `method` against the node, for everything but `generate`.
+fn handle_call(behaviour: &mut AppBehaviour, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    debug!("rpc {}", method);
+    let chain = &behaviour.node.blockchain;
+    match method {
+        "getblockcount" => Ok(json!(chain.len() - 1)),
+        "getbestblockhash" => Ok(json!(chain.chain.last().map(|block| block.hash.clone()))),
+        "getblock" => {
+            let height = match param(params, 0)? {
+                Value::Number(height) => height.as_u64().map(|height| height as usize),
+                Value::String(hash) => chain.height_of(hash),
+                _ => return Err(RpcError::new(INVALID_PARAMS, "expected a block hash or height")),
+            };
+            height
+                .and_then(|height| block_info(chain, height))
+                .ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))
+        }
+        "gettransaction" => {
+            let id = string_param(params, 0)?;
+            transaction_info(&behaviour.node, id).ok_or_else(|| RpcError::new(NOT_FOUND, "transaction not found"))
+        }
+        "sendrawtransaction" => {
+            let tx: Transaction = serde_json::from_value(param(params, 0)?.clone())
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid transaction: {}", e)))?;
+            match gossip::validate_transaction(&behaviour.node, &tx) {
+                MessageAcceptance::Accept => {}
+                MessageAcceptance::Ignore => return Err(RpcError::new(REJECTED, "transaction is already known")),
+                MessageAcceptance::Reject => {
+                    let reason = tx.validate().err().map(|e| e.to_string()).unwrap_or_default();
+                    return Err(RpcError::new(REJECTED, format!("invalid transaction: {}", reason)));
+                }
+            }
+            let id = tx.id();
+            behaviour.submit_transaction(tx);
+            Ok(json!(id))
+        }
+        "getbalance" => {
+            let address = match params.first() {
+                Some(_) => string_param(params, 0)?.to_string(),
+                None => behaviour.peer_id.to_string(),
+            };
+            Ok(json!({ "address": address, "balance": chain.balance(&address) }))
+        }
+        "getpeerinfo" => Ok(peer_info(behaviour)),
+        "getmempoolinfo" => Ok(mempool_info(&behaviour.node)),
+        "setgenerate" => {
+            let generate = param(params, 0)?
+                .as_bool()
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "parameter 0 has to be true or false"))?;
+            let blocks = match params.get(1) {
+                Some(blocks) => Some(
+                    blocks
+                        .as_u64()
+                        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "parameter 1 has to be a number of blocks"))?,
+                ),
+                None => None,
+            };
+            match generate {
+                true => behaviour.miner.start(blocks),
+                false => behaviour.miner.stop(),
+            }
+            Ok(mining_info(behaviour))
+        }
+        "getmininginfo" => Ok(mining_info(behaviour)),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+fn param(params: &[Value], index: usize) -> Result<&Value, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter {}", index)))
+}
+
+fn string_param(params: &[Value], index: usize) -> Result<&str, RpcError> {
+    param(params, index)?
+        .as_str()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("parameter {} has to be a string", index)))
+}
+
+/// The block at `height` with how deep it is buried.
+pub fn block_info(chain: &Blockchain, height: usize) -> Option<Value> {
+    let block = chain.chain.get(height)?;
+    Some(json!({
+        "height": height,
+        "confirmations": chain.len() - height,
+        "block": block,
+    }))
+}
+
+/// A pending or confirmed transaction, confirmed ones with the block they are in.
+pub fn transaction_info(node: &Node, id: &str) -> Option<Value> {
+    if let Some(tx) = node.pending_txs.iter().find(|tx| tx.id() == id) {
+        return Some(json!({ "tx": tx, "confirmations": 0 }));
+    }
+    let chain = &node.blockchain;
+    chain.chain.iter().enumerate().find_map(|(height, block)| {
+        let tx = block.transactions.iter().find(|tx| tx.id() == id)?;
+        Some(json!({
+            "tx": tx,
+            "confirmations": chain.len() - height,
+            "blockhash": block.hash,
+            "height": height,
+        }))
+    })
+}
+
+pub fn mempool_info(node: &Node) -> Value {
+    let pending = &node.pending_txs;
+    json!({
+        "size": pending.len(),
+        "bytes": pending.iter().map(encoded_size).sum::<usize>(),
+        "maxsize": node.max_pending_txs,
+    })
+}
+
+/// Whether the background miner runs, and what it mines on.
+pub fn mining_info(behaviour: &AppBehaviour) -> Value {
+    let node = &behaviour.node;
+    let (mining, blocks) = match behaviour.miner.mode() {
+        MiningMode::Stopped => (false, None),
+        MiningMode::Blocks(blocks) => (true, Some(blocks)),
+        MiningMode::Continuous => (true, None),
+    };
+    json!({
+        "mining": mining,
+        "blocks": blocks,
+        "height": node.blockchain.len() - 1,
+        "difficulty": node.blockchain.difficulty(),
+        "pending": node.pending_txs.len(),
+    })
+}
+
+/// Peers which completed the handshake, with what we know about them.
+pub fn peer_info(behaviour: &AppBehaviour) -> Value {
+    let peers: Vec<Value> = behaviour
+        .connected_peers()
+        .iter()
+        .map(|peer| {
+            let handshake = behaviour.peer_handshake(peer);
+            json!({
+                "peer_id": peer.to_string(),
+                "address": behaviour.peer_manager.peer_info(peer).map(|info| info.addr.to_string()),
+                "height": handshake.map(|handshake| handshake.best_height),
+                "user_agent": handshake.map(|handshake| handshake.user_agent.clone()),
+            })
+        })
+        .collect();
+    Value::Array(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::discovery::tests::local_swarm;
+    use crate::params::ChainParams;
+    use crate::p2p::{self, AppBehaviour};
+    use crate::rpc::{answer, handle_body, serve, RpcCall, RpcError, METHOD_NOT_FOUND, NOT_FOUND, REJECTED};
+    use libp2p::{futures::StreamExt, Swarm};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::select;
+    use tokio::sync::{mpsc, oneshot};
+
+    /// Answers a call like the event loop does, driving the swarm until the reply is in.
+    async fn call(swarm: &mut Swarm<AppBehaviour>, method: &str, params: Value) -> Result<Value, RpcError> {
+        let (reply, mut result) = oneshot::channel();
+        let params = params.as_array().cloned().unwrap_or_default();
+        answer(swarm.behaviour_mut(), RpcCall { method: method.to_string(), params, reply });
+        loop {
+            select! {
+                biased;
+                result = &mut result => return result.unwrap(),
+                event = swarm.select_next_some() => {
+                    p2p::handle_swarm_event(swarm, event);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_methods() {
+        let (mut swarm, _) = local_swarm(ChainParams::test()).await;
+        let swarm = &mut swarm;
+
+        assert_eq!(call(swarm, "getblockcount", json!([])).await, Ok(json!(0)));
+        let hashes = call(swarm, "generate", json!([2])).await.unwrap();
+        assert_eq!(call(swarm, "getblockcount", json!([])).await, Ok(json!(2)));
+        assert_eq!(call(swarm, "getbestblockhash", json!([])).await, Ok(hashes[1].clone()));
+        let block = call(swarm, "getblock", json!([hashes[0]])).await.unwrap();
+        assert_eq!(block["height"], 1);
+        assert_eq!(call(swarm, "getblock", json!([1])).await, Ok(block));
+        assert_eq!(call(swarm, "getblock", json!([7])).await.unwrap_err().code, NOT_FOUND);
+
+        let tx = generate_blocks()[0].transactions[0].clone();
+        assert_eq!(call(swarm, "sendrawtransaction", json!([tx])).await, Ok(json!(tx.id())));
+        assert_eq!(call(swarm, "sendrawtransaction", json!([tx])).await.unwrap_err().code, REJECTED);
+        assert_eq!(call(swarm, "getmempoolinfo", json!([])).await.unwrap()["size"], 1);
+        assert_eq!(call(swarm, "gettransaction", json!([tx.id()])).await.unwrap()["confirmations"], 0);
+
+        call(swarm, "generate", json!([])).await.unwrap();
+        let confirmed = call(swarm, "gettransaction", json!([tx.id()])).await.unwrap();
+        assert_eq!((confirmed["height"].clone(), confirmed["confirmations"].clone()), (json!(3), json!(1)));
+        assert_eq!(call(swarm, "getbalance", json!([tx.to])).await.unwrap()["balance"], tx.amount);
+        assert_eq!(call(swarm, "getpeerinfo", json!([])).await, Ok(json!([])));
+        let mining = call(swarm, "setgenerate", json!([true, 3])).await.unwrap();
+        assert_eq!((mining["mining"].clone(), mining["blocks"].clone()), (json!(true), json!(3)));
+        assert_eq!(call(swarm, "setgenerate", json!([false])).await.unwrap()["mining"], false);
+        assert_eq!(call(swarm, "getmininginfo", json!([])).await.unwrap()["height"], 3);
+        assert_eq!(call(swarm, "nope", json!([])).await.unwrap_err().code, METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let (mut swarm, _) = local_swarm(ChainParams::test()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (calls, mut call_rcv) = mpsc::unbounded_channel::<RpcCall>();
+        tokio::spawn(serve(listener, calls.clone()));
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    Some(call) = call_rcv.recv() => answer(swarm.behaviour_mut(), call),
+                    event = swarm.select_next_some() => {
+                        p2p::handle_swarm_event(&mut swarm, event);
+                    }
+                }
+            }
+        });
+
+        let body = r#"[{"jsonrpc":"2.0","method":"getblockcount","id":1},{"jsonrpc":"2.0","method":"generate"},{"jsonrpc":"1.0","method":"getblockcount","id":"x"}]"#;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let (_, json) = response.split_once("\r\n\r\n").unwrap();
+        let replies: Value = serde_json::from_str(json).unwrap();
+        // The notification is mined, but not answered
+        assert_eq!(replies[0], json!({ "jsonrpc": "2.0", "result": 0, "id": 1 }));
+        assert_eq!(replies[1]["id"], "x");
+        assert_eq!(replies.as_array().unwrap().len(), 2);
+
+        let garbage = handle_body(b"{", &calls).await.unwrap();
+        assert_eq!(garbage["error"]["code"], -32700);
+        let count = handle_body(br#"{"jsonrpc":"2.0","method":"getblockcount","id":2}"#, &calls).await;
+        assert_eq!(count.unwrap()["result"], 1);
+        let null_id = handle_body(br#"{"jsonrpc":"2.0","method":"getblockcount","id":null}"#, &calls).await;
+        assert_eq!(null_id, Some(json!({ "jsonrpc": "2.0", "result": 1, "id": null })));
+    }
+}
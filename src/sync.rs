@@ -0,0 +1,550 @@
+use crate::{
+    block::{Block, BlockHeader},
+    blockchain::Blockchain,
+    protocol::ChainRequest,
+};
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+pub const BLOCKS_PER_REQUEST: usize = 16;
+pub const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// Broadcast when a node mines a block or a peer connects, instead of the blocks themselves
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TipAnnouncement {
+    pub header: BlockHeader,
+    pub height: usize,
+}
+
+impl TipAnnouncement {
+    pub fn from_chain(chain: &Blockchain) -> Option<Self> {
+        chain.chain.last().map(|block| TipAnnouncement {
+            header: block.header(),
+            height: chain.len() - 1,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyncError {
+    UnexpectedHeaders,
+    TooManyHeaders(usize),
+    UnknownParent(String),
+    BrokenLink(String),
+    InvalidHash(String),
+    InsufficientWork(String),
+    NotEnoughWork { local: u128, remote: u128 },
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::UnexpectedHeaders => write!(f, "headers were not requested from this peer"),
+            SyncError::TooManyHeaders(n) => write!(f, "{} headers in one message", n),
+            SyncError::UnknownParent(hash) => write!(f, "headers fork off unknown block {}", hash),
+            SyncError::BrokenLink(hash) => write!(f, "header {} does not link to previous", hash),
+            SyncError::InvalidHash(hash) => write!(f, "header {} has invalid hash", hash),
+            SyncError::InsufficientWork(hash) => write!(f, "header {} does not meet difficulty", hash),
+            SyncError::NotEnoughWork { local, remote } => {
+                write!(f, "remote work {} does not exceed local work {}", remote, local)
+            }
+        }
+    }
+}
+
+/// Checks headers received in reply to our locator.
+/// Returns the height they fork off our chain at and their cumulative work.
+pub fn validate_headers(chain: &Blockchain, headers: &[BlockHeader]) -> Result<(usize, u128), SyncError> {
+    if headers.len() > MAX_HEADERS_PER_MESSAGE {
+        return Err(SyncError::TooManyHeaders(headers.len()));
+    }
+
+    let fork_height = match headers.first() {
+        None => return Ok((chain.len(), 0)),
+        Some(first) if first.prev_hash.is_empty() => 0,
+        Some(first) => match chain.height_of(&first.prev_hash) {
+            Some(height) => height + 1,
+            None => return Err(SyncError::UnknownParent(first.prev_hash.clone())),
+        },
+    };
+
+    let mut prev_hash = &headers[0].prev_hash;
+    let mut work = 0;
+    for header in headers {
+        if &header.prev_hash != prev_hash {
+            return Err(SyncError::BrokenLink(header.hash.clone()));
+        }
+        if !header.has_valid_hash() {
+            return Err(SyncError::InvalidHash(header.hash.clone()));
+        }
+        if !header.meets_difficulty(chain.difficulty()) {
+            return Err(SyncError::InsufficientWork(header.hash.clone()));
+        }
+        work += header.work();
+        prev_hash = &header.hash;
+    }
+
+    Ok((fork_height, work))
+}
+
+struct BodyDownload {
+    fork_height: usize,
+    headers: Vec<BlockHeader>,
+    index: HashMap<String, usize>,
+    bodies: HashMap<String, Block>,
+    queue: VecDeque<Vec<String>>,
+    // One batch per peer at a time, so the download is spread over all peers
+    in_flight: HashMap<PeerId, (Vec<String>, Instant)>,
+    // Peers which timed out or didn't have the blocks we asked for
+    exhausted: HashSet<PeerId>,
+}
+
+enum SyncState {
+    Idle,
+    Headers { peer: PeerId, since: Instant },
+    Bodies(BodyDownload),
+}
+
+pub type Outgoing = Vec<(PeerId, ChainRequest)>;
+
+/// Header-first synchronization: fetch headers from one peer using a block locator,
+/// check their work, then download only the missing bodies from all peers in parallel.
+pub struct HeaderSync {
+    state: SyncState,
+}
+
+impl Default for HeaderSync {
+    fn default() -> Self {
+        HeaderSync {
+            state: SyncState::Idle,
+        }
+    }
+}
+
+impl HeaderSync {
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, SyncState::Idle)
+    }
+
+    pub fn state(&self) -> &'static str {
+        match self.state {
+            SyncState::Idle => "idle",
+            SyncState::Headers { .. } => "headers",
+            SyncState::Bodies(_) => "bodies",
+        }
+    }
+
+    /// Blocks downloaded and blocks to download, while bodies are being fetched.
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        match &self.state {
+            SyncState::Bodies(download) => Some((download.bodies.len(), download.headers.len())),
+            _ => None,
+        }
+    }
+
+    /// Starts a new round if nothing is in progress, otherwise retries timed out requests.
+    pub fn start(&mut self, chain: &Blockchain, peers: &[PeerId], now: Instant) -> Outgoing {
+        if !self.is_idle() {
+            return self.tick(peers, now);
+        }
+
+        match peers.choose(&mut rand::thread_rng()) {
+            Some(peer) => self.request_headers(*peer, chain, now),
+            None => vec![],
+        }
+    }
+
+    /// Asks the announcing peer for headers if its tip is one we don't have and
+    /// might have more work than ours.
+    pub fn on_announcement(
+        &mut self,
+        from: PeerId,
+        tip: &TipAnnouncement,
+        chain: &Blockchain,
+        now: Instant,
+    ) -> Outgoing {
+        let known = chain.height_of(&tip.header.hash).is_some();
+        if known || tip.height + 1 < chain.len() || !self.is_idle() {
+            return vec![];
+        }
+        self.request_headers(from, chain, now)
+    }
+
+    fn request_headers(&mut self, peer: PeerId, chain: &Blockchain, now: Instant) -> Outgoing {
+        self.state = SyncState::Headers { peer, since: now };
+        vec![(
+            peer,
+            ChainRequest::GetHeaders {
+                locator: chain.locator(),
+            },
+        )]
+    }
+
+    pub fn on_headers(
+        &mut self,
+        from: PeerId,
+        headers: Vec<BlockHeader>,
+        chain: &Blockchain,
+        peers: &[PeerId],
+        now: Instant,
+    ) -> Result<Outgoing, SyncError> {
+        match self.state {
+            SyncState::Headers { peer, .. } if peer == from => self.state = SyncState::Idle,
+            _ => return Err(SyncError::UnexpectedHeaders),
+        }
+
+        if headers.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (fork_height, remote) = validate_headers(chain, &headers)?;
+        let local = chain.work_since(fork_height);
+        if remote <= local {
+            return Err(SyncError::NotEnoughWork { local, remote });
+        }
+
+        let index = headers
+            .iter()
+            .enumerate()
+            .map(|(i, header)| (header.hash.clone(), i))
+            .collect();
+        let queue = headers
+            .chunks(BLOCKS_PER_REQUEST)
+            .map(|batch| batch.iter().map(|header| header.hash.clone()).collect())
+            .collect();
+
+        self.state = SyncState::Bodies(BodyDownload {
+            fork_height,
+            headers,
+            index,
+            bodies: HashMap::new(),
+            queue,
+            in_flight: HashMap::new(),
+            exhausted: HashSet::new(),
+        });
+
+        Ok(self.assign(peers, now))
+    }
+
+    /// Stores the bodies matching the headers we are downloading.
+    /// Once all of them arrived, returns the candidate chain built from them.
+    pub fn on_blocks(
+        &mut self,
+        from: PeerId,
+        blocks: Vec<Block>,
+        chain: &Blockchain,
+        peers: &[PeerId],
+        now: Instant,
+    ) -> (Outgoing, Option<Blockchain>) {
+        let download = match &mut self.state {
+            SyncState::Bodies(download) => download,
+            _ => return (vec![], None),
+        };
+        let (batch, _) = match download.in_flight.remove(&from) {
+            Some(request) => request,
+            None => return (vec![], None),
+        };
+
+        let mut delivered = 0;
+        for block in blocks {
+            if !batch.contains(&block.hash) {
+                continue;
+            }
+            let header = &download.headers[download.index[&block.hash]];
+            if block.matches_header(header) {
+                download.bodies.insert(block.hash.clone(), block);
+                delivered += 1;
+            }
+        }
+
+        let missing: Vec<String> = batch
+            .into_iter()
+            .filter(|hash| !download.bodies.contains_key(hash))
+            .collect();
+        if !missing.is_empty() {
+            download.queue.push_front(missing);
+        }
+        if delivered == 0 {
+            download.exhausted.insert(from);
+        }
+
+        if download.bodies.len() == download.headers.len() {
+            let blocks = download
+                .headers
+                .iter()
+                .map(|header| download.bodies[&header.hash].clone())
+                .collect();
+            let candidate = chain.fork_at(download.fork_height, blocks);
+            self.state = SyncState::Idle;
+            return (vec![], Some(candidate));
+        }
+
+        (self.assign(peers, now), None)
+    }
+
+    /// Gives up on requests which took longer than `SYNC_REQUEST_TIMEOUT`
+    /// and hands their blocks to other peers.
+    pub fn tick(&mut self, peers: &[PeerId], now: Instant) -> Outgoing {
+        match &mut self.state {
+            SyncState::Idle => {}
+            SyncState::Headers { since, .. } => {
+                if now.duration_since(*since) > SYNC_REQUEST_TIMEOUT {
+                    self.state = SyncState::Idle;
+                }
+            }
+            SyncState::Bodies(download) => {
+                let expired: Vec<PeerId> = download
+                    .in_flight
+                    .iter()
+                    .filter(|(peer, (_, since))| {
+                        now.duration_since(*since) > SYNC_REQUEST_TIMEOUT || !peers.contains(peer)
+                    })
+                    .map(|(peer, _)| *peer)
+                    .collect();
+                for peer in expired {
+                    let (batch, _) = download.in_flight.remove(&peer).expect("peer is in flight");
+                    download.queue.push_front(batch);
+                    download.exhausted.insert(peer);
+                }
+            }
+        }
+
+        self.assign(peers, now)
+    }
+
+    /// A request to `peer` timed out or its connection dropped, ask another peer instead.
+    pub fn on_request_failed(
+        &mut self,
+        peer: PeerId,
+        chain: &Blockchain,
+        peers: &[PeerId],
+        now: Instant,
+    ) -> Outgoing {
+        match &mut self.state {
+            SyncState::Idle => vec![],
+            SyncState::Headers { peer: asked, .. } => {
+                if *asked != peer {
+                    return vec![];
+                }
+                self.state = SyncState::Idle;
+                let others: Vec<PeerId> = peers.iter().filter(|p| **p != peer).copied().collect();
+                self.start(chain, &others, now)
+            }
+            SyncState::Bodies(download) => {
+                if let Some((batch, _)) = download.in_flight.remove(&peer) {
+                    download.queue.push_front(batch);
+                }
+                download.exhausted.insert(peer);
+                self.assign(peers, now)
+            }
+        }
+    }
+
+    fn assign(&mut self, peers: &[PeerId], now: Instant) -> Outgoing {
+        let download = match &mut self.state {
+            SyncState::Bodies(download) => download,
+            _ => return vec![],
+        };
+
+        let mut requests = vec![];
+        for peer in peers {
+            if download.in_flight.contains_key(peer) || download.exhausted.contains(peer) {
+                continue;
+            }
+            let batch = match download.queue.pop_front() {
+                Some(batch) => batch,
+                None => break,
+            };
+            download.in_flight.insert(*peer, (batch.clone(), now));
+            requests.push((*peer, ChainRequest::GetBlocks { hashes: batch }));
+        }
+
+        // Nobody left to download from, the next round starts from scratch
+        if download.in_flight.is_empty() && !download.queue.is_empty() {
+            self.state = SyncState::Idle;
+        }
+
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{ChainRequest, ChainResponse};
+    use crate::sync::{HeaderSync, SyncError, TipAnnouncement, BLOCKS_PER_REQUEST, MAX_HEADERS_PER_MESSAGE};
+    use crate::{blockchain::Blockchain, transaction::Transaction};
+    use libp2p::PeerId;
+    use std::time::{Duration, Instant, SystemTime};
+
+    fn mine(chain: &mut Blockchain, blocks: usize) {
+        for i in 0..blocks {
+            let tx = Transaction {
+                from: String::from("Alice"),
+                to: String::from("Bob"),
+                time: SystemTime::now(),
+                amount: i as i32,
+            };
+            assert!(chain.try_mine(vec![tx]).is_ok());
+        }
+    }
+
+    // Plays the role of the remote peers, which all share `remote`
+    fn respond(remote: &Blockchain, request: &ChainRequest) -> ChainResponse {
+        match request {
+            ChainRequest::GetHeaders { locator } => {
+                ChainResponse::Headers(remote.headers_after(locator, MAX_HEADERS_PER_MESSAGE))
+            }
+            ChainRequest::GetBlocks { hashes } => ChainResponse::Blocks(remote.blocks_by_hash(hashes)),
+            ChainRequest::GetTransactions { .. }
+            | ChainRequest::GetBlockTransactions { .. }
+            | ChainRequest::StemTransaction { .. } => panic!("sync never asks for transactions"),
+        }
+    }
+
+    #[test]
+    fn test_sync_downloads_missing_bodies_from_all_peers() {
+        let mut local = Blockchain::new(1, 1, 256);
+        mine(&mut local, 3);
+        let mut remote = local.clone();
+        mine(&mut remote, BLOCKS_PER_REQUEST * 2 + 1);
+
+        let peers = vec![PeerId::random(), PeerId::random()];
+        let now = Instant::now();
+        let mut sync = HeaderSync::default();
+
+        let requests = sync.start(&local, &peers, now);
+        assert_eq!(requests.len(), 1);
+        let (peer, request) = &requests[0];
+        let headers = match respond(&remote, request) {
+            ChainResponse::Headers(headers) => headers,
+            _ => unreachable!(),
+        };
+        assert_eq!(headers.len(), BLOCKS_PER_REQUEST * 2 + 1);
+
+        let mut requests = sync.on_headers(*peer, headers, &local, &peers, now).unwrap();
+        // Both peers got a batch
+        assert_eq!(requests.len(), 2);
+
+        let mut candidate = None;
+        while let Some((peer, request)) = requests.pop() {
+            let blocks = match respond(&remote, &request) {
+                ChainResponse::Blocks(blocks) => blocks,
+                _ => unreachable!(),
+            };
+            let (more, done) = sync.on_blocks(peer, blocks, &local, &peers, now);
+            requests.extend(more);
+            if done.is_some() {
+                candidate = done;
+            }
+        }
+
+        assert!(candidate.unwrap() == remote);
+        assert!(sync.is_idle());
+    }
+
+    #[test]
+    fn test_sync_rejects_bad_headers() {
+        let mut local = Blockchain::new(1, 1, 256);
+        mine(&mut local, 3);
+        let mut remote = local.clone();
+        mine(&mut remote, 2);
+
+        let peer = PeerId::random();
+        let now = Instant::now();
+        let mut sync = HeaderSync::default();
+
+        assert_eq!(
+            sync.on_headers(peer, vec![], &local, &[peer], now),
+            Err(SyncError::UnexpectedHeaders)
+        );
+
+        sync.start(&local, &[peer], now);
+        let mut headers = remote.headers_after(&local.locator(), MAX_HEADERS_PER_MESSAGE);
+        headers[1].nonce += 1;
+        assert!(matches!(
+            sync.on_headers(peer, headers, &local, &[peer], now),
+            Err(SyncError::InvalidHash(_))
+        ));
+
+        // Our own blocks carry exactly as much work as our chain, which is not enough
+        sync.start(&local, &[peer], now);
+        let headers = local.headers_after(&[local.chain[0].hash.clone()], MAX_HEADERS_PER_MESSAGE);
+        assert!(matches!(
+            sync.on_headers(peer, headers, &local, &[peer], now),
+            Err(SyncError::NotEnoughWork { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sync_retries_other_peer_on_timeout() {
+        let local = Blockchain::new(1, 1, 256);
+        let mut remote = local.clone();
+        mine(&mut remote, 2);
+
+        let slow = PeerId::random();
+        let fast = PeerId::random();
+        let now = Instant::now();
+        let mut sync = HeaderSync::default();
+
+        sync.start(&local, &[slow], now);
+        let headers = remote.headers_after(&[], MAX_HEADERS_PER_MESSAGE);
+        let requests = sync.on_headers(slow, headers, &local, &[slow], now).unwrap();
+        assert_eq!(requests[0].0, slow);
+
+        let later = now + Duration::from_secs(60);
+        let requests = sync.tick(&[slow, fast], later);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, fast);
+
+        let blocks = remote.blocks_by_hash(&[remote.chain[0].hash.clone(), remote.chain[1].hash.clone()]);
+        let (_, candidate) = sync.on_blocks(fast, blocks, &local, &[slow, fast], later);
+        assert!(candidate.unwrap() == remote);
+    }
+
+    #[test]
+    fn test_sync_asks_another_peer_for_headers_on_failure() {
+        let local = Blockchain::new(1, 1, 256);
+        let peers = vec![PeerId::random(), PeerId::random()];
+        let now = Instant::now();
+        let mut sync = HeaderSync::default();
+
+        let first = sync.start(&local, &peers, now)[0].0;
+        let retry = sync.on_request_failed(first, &local, &peers, now);
+        assert_eq!(retry.len(), 1);
+        assert_ne!(retry[0].0, first);
+        assert!(matches!(retry[0].1, ChainRequest::GetHeaders { .. }));
+    }
+
+    #[test]
+    fn test_announcement_requests_only_unknown_tips() {
+        let mut local = Blockchain::new(1, 1, 256);
+        mine(&mut local, 2);
+        let mut remote = local.clone();
+        mine(&mut remote, 1);
+
+        let peer = PeerId::random();
+        let now = Instant::now();
+        let mut sync = HeaderSync::default();
+
+        let ours = TipAnnouncement::from_chain(&local).unwrap();
+        assert!(sync.on_announcement(peer, &ours, &local, now).is_empty());
+
+        let mut behind = TipAnnouncement::from_chain(&remote).unwrap();
+        behind.header.hash = String::from("unknown");
+        behind.height = 0;
+        assert!(sync.on_announcement(peer, &behind, &local, now).is_empty());
+
+        let ahead = TipAnnouncement::from_chain(&remote).unwrap();
+        assert_eq!(ahead.height, 2);
+        let requests = sync.on_announcement(peer, &ahead, &local, now);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, peer);
+        assert!(!sync.is_idle());
+    }
+}
@@ -1,8 +1,11 @@
 use std::time::SystemTime;
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp::PartialEq;
 
+use crate::error::TransactionError;
+
 #[derive(Serialize, Deserialize, Clone, Display, PartialEq, Debug)]
 #[display(fmt = "from {} to {} amt {}", from, to, amount)]
 pub struct Transaction {
@@ -11,3 +14,30 @@ pub struct Transaction {
     pub time: SystemTime,
     pub amount: i32,
 }
+
+impl Transaction {
+    pub fn id(&self) -> String {
+        let tx_string = serde_json::to_string(&self).expect("can jsonify transaction");
+
+        let hashed = Sha256::new().chain_update(tx_string).finalize();
+
+        format!("{:x}", hashed)
+    }
+
+    pub fn validate(&self) -> Result<(), TransactionError> {
+        if self.amount <= 0 {
+            return Err(TransactionError::NonPositiveAmount(self.amount));
+        }
+        if self.from.is_empty() || self.to.is_empty() {
+            return Err(TransactionError::MissingParty);
+        }
+        if self.from == self.to {
+            return Err(TransactionError::SelfTransfer);
+        }
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+}
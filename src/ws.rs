@@ -0,0 +1,450 @@
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    block::Block,
+    events::Event,
+    http::{self, Request, Response},
+    transaction::Transaction,
+};
+
+pub const DEFAULT_WS_ADDR: &str = "127.0.0.1:8546";
+/// Events a subscriber may fall behind by before it is dropped.
+pub const EVENT_BUFFER: usize = 1024;
+/// Messages from clients are only subscriptions, so they are small.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const MAX_ADDRESSES: usize = 100;
+
+// Just enough of RFC 6455 for notifications: unfragmented text messages from
+// the client, pings and closing. Anything else closes the connection.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_POLICY: u16 = 1008;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Topic {
+    Blocks,
+    Mempool,
+    Reorgs,
+    Address,
+}
+
+impl Topic {
+    fn name(self) -> &'static str {
+        match self {
+            Topic::Blocks => "blocks",
+            Topic::Mempool => "mempool",
+            Topic::Reorgs => "reorgs",
+            Topic::Address => "address",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ClientRequest {
+    op: String,
+    topic: Topic,
+    #[serde(default)]
+    address: Option<String>,
+}
+
+/// What one connection subscribed to.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    blocks: bool,
+    mempool: bool,
+    reorgs: bool,
+    addresses: HashSet<String>,
+}
+
+impl Subscriptions {
+    /// Applies a `subscribe` or `unsubscribe` request and returns the reply.
+    pub fn handle(&mut self, message: &str) -> Value {
+        let request: ClientRequest = match serde_json::from_str(message) {
+            Ok(request) => request,
+            Err(e) => return json!({ "error": format!("invalid request: {}", e) }),
+        };
+        let subscribe = match request.op.as_str() {
+            "subscribe" => true,
+            "unsubscribe" => false,
+            op => return json!({ "error": format!("unknown op {}", op) }),
+        };
+        let topic = request.topic.name();
+        match (request.topic, request.address) {
+            (Topic::Address, None) => return json!({ "error": "address topic needs an address" }),
+            (Topic::Address, Some(address)) if subscribe => {
+                if self.addresses.len() >= MAX_ADDRESSES && !self.addresses.contains(&address) {
+                    return json!({ "error": format!("at most {} addresses per connection", MAX_ADDRESSES) });
+                }
+                self.addresses.insert(address);
+            }
+            (Topic::Address, Some(address)) => {
+                self.addresses.remove(&address);
+            }
+            (Topic::Blocks, _) => self.blocks = subscribe,
+            (Topic::Mempool, _) => self.mempool = subscribe,
+            (Topic::Reorgs, _) => self.reorgs = subscribe,
+        }
+        json!({ "ok": request.op, "topic": topic })
+    }
+
+    /// The messages `event` means for this connection, if any.
+    pub fn messages(&self, event: &Event) -> Vec<Value> {
+        let mut messages = vec![];
+        match event {
+            Event::BlockConnected { height, block } => {
+                if self.blocks {
+                    messages.push(json!({ "event": "block", "height": height, "hash": block.hash, "block": block }));
+                }
+                messages.extend(self.block_activity(*height, block, false));
+            }
+            Event::BlockDisconnected { height, block } => {
+                if self.reorgs {
+                    messages.push(json!({ "event": "disconnected", "height": height, "hash": block.hash }));
+                }
+                messages.extend(self.block_activity(*height, block, true));
+            }
+            Event::TxAccepted(tx) => {
+                if self.mempool {
+                    messages.push(json!({ "event": "tx", "tx": tx }));
+                }
+                for (address, direction) in self.touched(tx) {
+                    messages.push(json!({ "event": "address", "address": address, "direction": direction, "tx": tx }));
+                }
+            }
+            _ => {}
+        }
+        messages
+    }
+
+    fn block_activity(&self, height: usize, block: &Block, disconnected: bool) -> Vec<Value> {
+        let mut messages = vec![];
+        for tx in &block.transactions {
+            for (address, direction) in self.touched(tx) {
+                let mut message = json!({
+                    "event": "address",
+                    "address": address,
+                    "direction": direction,
+                    "tx": tx,
+                    "height": height,
+                    "blockhash": block.hash,
+                });
+                if disconnected {
+                    message["disconnected"] = json!(true);
+                }
+                messages.push(message);
+            }
+        }
+        messages
+    }
+
+    fn touched<'a>(&'a self, tx: &'a Transaction) -> impl Iterator<Item = (&'a str, &'static str)> {
+        let sent = Some((tx.from.as_str(), "out")).filter(|(address, _)| self.addresses.contains(*address));
+        let received = Some((tx.to.as_str(), "in")).filter(|(address, _)| self.addresses.contains(*address));
+        sent.into_iter().chain(received)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Reads a frame from a client, which has to mask it.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await?;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("client frames have to be masked"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid("message too large"));
+    }
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin: head[0] & 0x80 != 0,
+        opcode: head[0] & 0x0f,
+        payload,
+    })
+}
+
+/// Writes a single unmasked frame, as servers do.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            head.push(126);
+            head.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend((len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+/// `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(sha1_smol::Sha1::from(format!("{}{}", key, GUID)).digest().bytes())
+}
+
+fn upgrade_key(request: &Request) -> Option<&str> {
+    let upgrade = request.header("upgrade")?.eq_ignore_ascii_case("websocket");
+    let version = request.header("sec-websocket-version")? == "13";
+    match request.method == "GET" && upgrade && version {
+        true => request.header("sec-websocket-key"),
+        false => None,
+    }
+}
+
+/// Accepts subscribers on `listener` for good, sending them what they asked for from `events`.
+pub async fn serve(listener: TcpListener, events: broadcast::Sender<Event>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(handle_connection(stream, addr, events.subscribe()));
+            }
+            Err(e) => debug!("ws accept failed: {}", e),
+        }
+    }
+}
+
+// Messages from the client, read on their own task so a half read frame is never lost
+enum ClientMessage {
+    Text(String),
+    Ping(Vec<u8>),
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, events: broadcast::Receiver<Event>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let request = match http::read_request(&mut reader).await {
+        Ok(Some(request)) => request,
+        Ok(None) => return,
+        Err(e) => {
+            debug!("bad ws request from {}: {}", addr, e);
+            let _ = http::write_response(&mut writer, &Response::empty(400)).await;
+            return;
+        }
+    };
+    let key = match upgrade_key(&request) {
+        Some(key) => key,
+        None => {
+            let _ = http::write_response(&mut writer, &Response::empty(400)).await;
+            return;
+        }
+    };
+    let mut response = Response::empty(101);
+    response.headers = vec![
+        (String::from("Upgrade"), String::from("websocket")),
+        (String::from("Connection"), String::from("Upgrade")),
+        (String::from("Sec-WebSocket-Accept"), accept_key(key)),
+    ];
+    if http::write_response(&mut writer, &response).await.is_err() {
+        return;
+    }
+
+    let (messages, message_rcv) = mpsc::unbounded_channel();
+    let read_task = tokio::spawn(read_messages(reader, addr, messages));
+    if let Err(e) = notify(&mut writer, message_rcv, events).await {
+        debug!("ws connection to {} failed: {}", addr, e);
+    }
+    read_task.abort();
+}
+
+async fn read_messages(mut reader: BufReader<OwnedReadHalf>, addr: SocketAddr, messages: mpsc::UnboundedSender<ClientMessage>) {
+    loop {
+        let message = match read_frame(&mut reader).await {
+            Ok(Frame { fin: true, opcode: OP_TEXT, payload }) => match String::from_utf8(payload) {
+                Ok(text) => ClientMessage::Text(text),
+                Err(_) => return,
+            },
+            Ok(Frame { opcode: OP_PING, payload, .. }) => ClientMessage::Ping(payload),
+            Ok(Frame { opcode: OP_PONG, .. }) => continue,
+            // Closing, or nothing we take, either way the connection ends
+            Ok(_) => return,
+            Err(e) => {
+                debug!("ws read from {} failed: {}", addr, e);
+                return;
+            }
+        };
+        if messages.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+async fn notify(
+    writer: &mut OwnedWriteHalf,
+    mut messages: mpsc::UnboundedReceiver<ClientMessage>,
+    mut events: broadcast::Receiver<Event>,
+) -> io::Result<()> {
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        select! {
+            message = messages.recv() => match message {
+                Some(ClientMessage::Text(text)) => {
+                    let reply = subscriptions.handle(&text);
+                    write_frame(writer, OP_TEXT, reply.to_string().as_bytes()).await?;
+                }
+                Some(ClientMessage::Ping(payload)) => write_frame(writer, OP_PONG, &payload).await?,
+                None => return write_frame(writer, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()).await,
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    for message in subscriptions.messages(&event) {
+                        write_frame(writer, OP_TEXT, message.to_string().as_bytes()).await?;
+                    }
+                }
+                // Dropped rather than sent a gap in the events
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let mut payload = CLOSE_POLICY.to_be_bytes().to_vec();
+                    payload.extend_from_slice(b"too slow");
+                    return write_frame(writer, OP_CLOSE, &payload).await;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return write_frame(writer, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()).await;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::tests::generate_blocks;
+    use crate::events::Event;
+    use crate::ws::{accept_key, read_frame, serve, write_frame, Subscriptions};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+
+    // A frame as a client sends it
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let frame = read_frame(&mut masked(1, b"hello").as_slice()).await.unwrap();
+        assert_eq!((frame.fin, frame.opcode, frame.payload.as_slice()), (true, 1, &b"hello"[..]));
+        let mut unmasked = vec![];
+        write_frame(&mut unmasked, 1, b"hello").await.unwrap();
+        assert_eq!(unmasked, b"\x81\x05hello");
+        assert!(read_frame(&mut unmasked.as_slice()).await.is_err());
+
+        let mut long = vec![];
+        write_frame(&mut long, 1, &[0; 300]).await.unwrap();
+        assert_eq!(&long[..4], &[0x81, 126, 1, 44]);
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let tx = generate_blocks()[0].transactions[0].clone();
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.messages(&Event::TxAccepted(tx.clone())).is_empty());
+
+        let request = json!({ "op": "subscribe", "topic": "address", "address": tx.to });
+        assert_eq!(subscriptions.handle(&request.to_string()), json!({ "ok": "subscribe", "topic": "address" }));
+        let messages = subscriptions.messages(&Event::TxAccepted(tx.clone()));
+        assert_eq!(messages, vec![json!({ "event": "address", "address": tx.to, "direction": "in", "tx": tx })]);
+
+        let mut block = generate_blocks()[0].clone();
+        block.transactions = vec![tx.clone()];
+        let reverted = subscriptions.messages(&Event::BlockDisconnected { height: 3, block });
+        assert_eq!((reverted.len(), reverted[0]["disconnected"].clone()), (1, json!(true)));
+
+        assert!(subscriptions.handle(r#"{"op":"subscribe","topic":"address"}"#)["error"].is_string());
+        assert!(subscriptions.handle(r#"{"op":"subscribe","topic":"weather"}"#)["error"].is_string());
+        assert!(subscriptions.handle("nope")["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, _) = broadcast::channel(16);
+        tokio::spawn(serve(listener, events.clone()));
+
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let handshake = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            stream.read_line(&mut head).await.unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // Server frames are unmasked, with a two byte length past 125
+        async fn read_text(stream: &mut BufReader<TcpStream>) -> Value {
+            let mut head = [0; 2];
+            stream.read_exact(&mut head).await.unwrap();
+            let len = match head[1] {
+                126 => stream.read_u16().await.unwrap() as usize,
+                len => len as usize,
+            };
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await.unwrap();
+            serde_json::from_slice(&payload).unwrap()
+        }
+
+        stream
+            .write_all(&masked(1, br#"{"op":"subscribe","topic":"mempool"}"#))
+            .await
+            .unwrap();
+        assert_eq!(read_text(&mut stream).await["ok"], "subscribe");
+        let tx = generate_blocks()[0].transactions[0].clone();
+        events.send(Event::TxAccepted(tx.clone())).unwrap();
+        assert_eq!(read_text(&mut stream).await, json!({ "event": "tx", "tx": tx }));
+
+        stream.write_all(&masked(8, &[])).await.unwrap();
+        let mut close = [0; 2];
+        stream.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2]);
+    }
+}